@@ -9,7 +9,9 @@
  * except according to those terms.
  */
 
-use std::io::{self, Write};
+use alloc::vec::Vec;
+
+use crate::io::{self, Write};
 
 const CHARPAD: u8 = b'=';
 
@@ -20,118 +22,88 @@ pub fn base64_encode(input: &[u8]) -> io::Result<Vec<u8>> {
     Ok(buf)
 }
 
+/// Returns the length of the Base64 encoding of `len` bytes, including
+/// line breaks when `is_inline` is false.
+pub fn base64_encoded_len(len: usize, is_inline: bool) -> usize {
+    let base64_len = (len * 4 / 3 + 3) & !3;
+    if !is_inline {
+        base64_len + 2 * (base64_len / 76 + 1)
+    } else {
+        base64_len
+    }
+}
+
+/// Input bytes encoded into each line of 76 characters.
+const LINE_INPUT_LEN: usize = 57;
+
 pub fn base64_encode_mime(
     input: &[u8],
     mut output: impl Write,
     is_inline: bool,
 ) -> io::Result<usize> {
-    let mut i = 0;
-    let mut t1;
-    let mut t2;
-    let mut t3;
+    let mut buf = [0u8; 78];
     let mut bytes_written = 0;
 
-    if input.len() > 2 {
-        while i < input.len() - 2 {
-            #[cfg(not(feature = "ludicrous_mode"))]
-            {
-                t1 = input[i];
-                t2 = input[i + 1];
-                t3 = input[i + 2];
-
-                output.write_all(&[
-                    E0[t1 as usize],
-                    E1[(((t1 & 0x03) << 4) | ((t2 >> 4) & 0x0F)) as usize],
-                    E1[(((t2 & 0x0F) << 2) | ((t3 >> 6) & 0x03)) as usize],
-                    E2[t3 as usize],
-                ])?;
-            }
-
-            #[cfg(feature = "ludicrous_mode")]
-            unsafe {
-                t1 = *input.get_unchecked(i);
-                t2 = *input.get_unchecked(i + 1);
-                t3 = *input.get_unchecked(i + 2);
-
-                output.write_all(&[
-                    *E0.get_unchecked(t1 as usize),
-                    *E1.get_unchecked((((t1 & 0x03) << 4) | ((t2 >> 4) & 0x0F)) as usize),
-                    *E1.get_unchecked((((t2 & 0x0F) << 2) | ((t3 >> 6) & 0x03)) as usize),
-                    *E2.get_unchecked(t3 as usize),
-                ])?;
-            }
-
-            bytes_written += 4;
-
-            if !is_inline && bytes_written % 19 == 0 {
-                output.write_all(b"\r\n")?;
-            }
-
-            i += 3;
+    for chunk in input.chunks(LINE_INPUT_LEN) {
+        let mut len = 0;
+        let mut triplets = chunk.chunks_exact(3);
+        for triplet in &mut triplets {
+            buf[len..len + 4].copy_from_slice(&encode_triplet(triplet[0], triplet[1], triplet[2]));
+            len += 4;
         }
-    }
-
-    let remaining = input.len() - i;
-    if remaining > 0 {
-        #[cfg(not(feature = "ludicrous_mode"))]
-        {
-            t1 = input[i];
-            if remaining == 1 {
-                output.write_all(&[
-                    E0[t1 as usize],
-                    E1[((t1 & 0x03) << 4) as usize],
-                    CHARPAD,
-                    CHARPAD,
-                ])?;
-            } else {
-                t2 = input[i + 1];
-                output.write_all(&[
-                    E0[t1 as usize],
-                    E1[(((t1 & 0x03) << 4) | ((t2 >> 4) & 0x0F)) as usize],
-                    E2[((t2 & 0x0F) << 2) as usize],
-                    CHARPAD,
-                ])?;
+        match *triplets.remainder() {
+            [t1] => {
+                let quad = encode_triplet(t1, 0, 0);
+                buf[len..len + 4].copy_from_slice(&[quad[0], quad[1], CHARPAD, CHARPAD]);
+                len += 4;
             }
-        }
-
-        #[cfg(feature = "ludicrous_mode")]
-        unsafe {
-            t1 = *input.get_unchecked(i);
-            if remaining == 1 {
-                output.write_all(&[
-                    *E0.get_unchecked(t1 as usize),
-                    *E1.get_unchecked(((t1 & 0x03) << 4) as usize),
-                    CHARPAD,
-                    CHARPAD,
-                ])?;
-            } else {
-                t2 = *input.get_unchecked(i + 1);
-                output.write_all(&[
-                    *E0.get_unchecked(t1 as usize),
-                    *E1.get_unchecked((((t1 & 0x03) << 4) | ((t2 >> 4) & 0x0F)) as usize),
-                    *E2.get_unchecked(((t2 & 0x0F) << 2) as usize),
-                    CHARPAD,
-                ])?;
+            [t1, t2] => {
+                let quad = encode_triplet(t1, t2, 0);
+                buf[len..len + 4].copy_from_slice(&[quad[0], quad[1], quad[2], CHARPAD]);
+                len += 4;
             }
+            _ => (),
         }
+        bytes_written += len;
 
-        bytes_written += 4;
-
-        if !is_inline && bytes_written % 19 == 0 {
-            output.write_all(b"\r\n")?;
+        if !is_inline {
+            buf[len..len + 2].copy_from_slice(b"\r\n");
+            output.write_all(&buf[..len + 2])?;
+        } else {
+            output.write_all(&buf[..len])?;
         }
     }
 
-    if !is_inline && bytes_written % 19 != 0 {
-        output.write_all(b"\r\n")?;
+    Ok(bytes_written)
+}
+
+#[inline(always)]
+fn encode_triplet(t1: u8, t2: u8, t3: u8) -> [u8; 4] {
+    #[cfg(not(feature = "ludicrous_mode"))]
+    {
+        [
+            E0[t1 as usize],
+            E1[(((t1 & 0x03) << 4) | ((t2 >> 4) & 0x0F)) as usize],
+            E1[(((t2 & 0x0F) << 2) | ((t3 >> 6) & 0x03)) as usize],
+            E2[t3 as usize],
+        ]
     }
 
-    Ok(bytes_written)
+    #[cfg(feature = "ludicrous_mode")]
+    unsafe {
+        [
+            *E0.get_unchecked(t1 as usize),
+            *E1.get_unchecked((((t1 & 0x03) << 4) | ((t2 >> 4) & 0x0F)) as usize),
+            *E1.get_unchecked((((t2 & 0x0F) << 2) | ((t3 >> 6) & 0x03)) as usize),
+            *E2.get_unchecked(t3 as usize),
+        ]
+    }
 }
 
 #[cfg(test)]
 #[allow(clippy::items_after_test_module)]
 mod tests {
+    use alloc::{string::ToString, vec::Vec};
 
     #[test]
     fn encode_base64() {
@@ -161,10 +133,29 @@ mod tests {
                 ),
                 false,
             ),
+            ("".to_string(), "", false),
+            (
+                "a".repeat(57),
+                "YWFhYWFhYWFhYWFhYWFhYWFhYWFhYWFhYWFhYWFhYWFhYWFhYWFhYWFhYWFhYWFhYWFhYWFhYWFh\r\n",
+                false,
+            ),
+            (
+                "a".repeat(58),
+                concat!(
+                    "YWFhYWFhYWFhYWFhYWFhYWFhYWFhYWFhYWFhYWFhYWFhYWFhYWFhYWFhYWFhYWFhYWFhYWFhYWFh\r\n",
+                    "YQ==\r\n"
+                ),
+                false,
+            ),
+            (
+                "a".repeat(58),
+                "YWFhYWFhYWFhYWFhYWFhYWFhYWFhYWFhYWFhYWFhYWFhYWFhYWFhYWFhYWFhYWFhYWFhYWFhYWFhYQ==",
+                true,
+            ),
         ] {
             let mut output = Vec::new();
             super::base64_encode_mime(input.as_bytes(), &mut output, is_inline).unwrap();
-            assert_eq!(std::str::from_utf8(&output).unwrap(), expected_result);
+            assert_eq!(core::str::from_utf8(&output).unwrap(), expected_result);
         }
     }
 }
@@ -181,6 +172,7 @@ mod tests {
  *
  */
 
+#[allow(clippy::byte_char_slices)]
 pub static E0: &[u8] = &[
     b'A', b'A', b'A', b'A', b'B', b'B', b'B', b'B', b'C', b'C', b'C', b'C', b'D', b'D', b'D', b'D',
     b'E', b'E', b'E', b'E', b'F', b'F', b'F', b'F', b'G', b'G', b'G', b'G', b'H', b'H', b'H', b'H',
@@ -200,6 +192,7 @@ pub static E0: &[u8] = &[
     b'8', b'8', b'8', b'8', b'9', b'9', b'9', b'9', b'+', b'+', b'+', b'+', b'/', b'/', b'/', b'/',
 ];
 
+#[allow(clippy::byte_char_slices)]
 pub static E1: &[u8] = &[
     b'A', b'B', b'C', b'D', b'E', b'F', b'G', b'H', b'I', b'J', b'K', b'L', b'M', b'N', b'O', b'P',
     b'Q', b'R', b'S', b'T', b'U', b'V', b'W', b'X', b'Y', b'Z', b'a', b'b', b'c', b'd', b'e', b'f',
@@ -219,6 +212,7 @@ pub static E1: &[u8] = &[
     b'w', b'x', b'y', b'z', b'0', b'1', b'2', b'3', b'4', b'5', b'6', b'7', b'8', b'9', b'+', b'/',
 ];
 
+#[allow(clippy::byte_char_slices)]
 pub static E2: &[u8] = &[
     b'A', b'B', b'C', b'D', b'E', b'F', b'G', b'H', b'I', b'J', b'K', b'L', b'M', b'N', b'O', b'P',
     b'Q', b'R', b'S', b'T', b'U', b'V', b'W', b'X', b'Y', b'Z', b'a', b'b', b'c', b'd', b'e', b'f',
@@ -0,0 +1,124 @@
+/*
+ * Copyright Stalwart Labs Ltd. See the COPYING
+ * file at the top-level directory of this distribution.
+ *
+ * Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+ * https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+ * <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+ * option. This file may not be copied, modified, or distributed
+ * except according to those terms.
+ */
+
+use crate::{
+    io::{self, Write},
+    LineEnding,
+};
+
+/// Writer that forwards all bytes to an inner writer while keeping
+/// track of the number of bytes written.
+pub struct CountingWriter<W: Write> {
+    inner: W,
+    count: usize,
+    tail: [u8; 2],
+}
+
+impl<W: Write> CountingWriter<W> {
+    /// Wrap a writer.
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            count: 0,
+            tail: [0; 2],
+        }
+    }
+
+    /// Returns the number of bytes written so far.
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// Returns a reference to the inner writer.
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    /// Returns a mutable reference to the inner writer. Bytes written
+    /// directly to it are not counted.
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    /// Unwrap the inner writer.
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+
+    /// Returns `true` if the last bytes written were a CRLF.
+    pub fn ends_with_crlf(&self) -> bool {
+        self.tail == *b"\r\n"
+    }
+
+    /// Returns `true` if the last bytes written were the given line ending.
+    pub(crate) fn ends_with_line_ending(&self, line_ending: LineEnding) -> bool {
+        match line_ending {
+            LineEnding::CrLf => self.ends_with_crlf(),
+            LineEnding::Lf => self.tail[1] == b'\n',
+        }
+    }
+
+    fn update_tail(&mut self, buf: &[u8]) {
+        match buf {
+            [] => (),
+            [ch] => self.tail = [self.tail[1], *ch],
+            [.., a, b] => self.tail = [*a, *b],
+        }
+    }
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let bytes_written = self.inner.write(buf)?;
+        self.count += bytes_written;
+        self.update_tail(&buf[..bytes_written]);
+        Ok(bytes_written)
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.inner.write_all(buf)?;
+        self.count += buf.len();
+        self.update_tail(buf);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use crate::{encoders::CountingWriter, io::Write, MessageBuilder};
+
+    #[test]
+    fn count_bytes() {
+        let mut writer = CountingWriter::new(Vec::new());
+        writer.write_all(b"Hello").unwrap();
+        assert!(!writer.ends_with_crlf());
+        writer.write_all(b",\r").unwrap();
+        writer.write_all(b"\n").unwrap();
+        assert!(writer.ends_with_crlf());
+        assert_eq!(writer.count(), 8);
+        assert_eq!(writer.get_ref(), b"Hello,\r\n");
+        assert_eq!(writer.into_inner(), b"Hello,\r\n");
+
+        let mut writer = CountingWriter::new(Vec::new());
+        MessageBuilder::new()
+            .from("john@doe.com")
+            .text_body("Hello, world!")
+            .write_to(&mut writer)
+            .unwrap();
+        assert_eq!(writer.count(), writer.get_ref().len());
+    }
+}
@@ -9,28 +9,71 @@
  * except according to those terms.
  */
 
-use std::io::{self, Write};
+use alloc::{borrow::Cow, vec::Vec};
 
-use super::{base64::base64_encode_mime, quoted_printable::quoted_printable_encode};
+use crate::io::{self, Write};
 
+use super::{
+    base64::{base64_encode_mime, base64_encoded_len},
+    quoted_printable::{quoted_printable_encode, QpMode},
+};
+
+/// Content-Transfer-Encoding chosen for a body or header value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum EncodingType {
+    /// Base64, used when most of the input needs escaping.
     Base64,
+    /// Quoted-printable. The flag is `true` when the input is entirely
+    /// ASCII and only needs escaping for other reasons (such as `=`, trailing
+    /// whitespace or long lines), and `false` when it contains non-ASCII
+    /// (usually UTF-8) bytes.
     QuotedPrintable(bool),
+    /// No encoding needed (7bit).
     None,
 }
 
+/// Returns the encoding the builder would choose for `input`.
+///
+/// Set `is_inline` for RFC 2047 encoded-words in headers, and `is_body` for
+/// text bodies, where line breaks are kept rather than escaped.
+///
+/// ```
+/// use mail_builder::encoders::encode::{get_encoding_type, EncodingType};
+///
+/// let transfer_encoding = match get_encoding_type("¡Hola!".as_bytes(), false, true) {
+///     EncodingType::Base64 => "base64",
+///     EncodingType::QuotedPrintable(_) => "quoted-printable",
+///     EncodingType::None => "7bit",
+/// };
+/// assert_eq!(transfer_encoding, "quoted-printable");
+/// assert_eq!(get_encoding_type(b"Hello", false, true), EncodingType::None);
+/// ```
 pub fn get_encoding_type(input: &[u8], is_inline: bool, is_body: bool) -> EncodingType {
+    get_encoding_type_and_len(input, is_inline, is_body).0
+}
+
+/// Returns the encoding type along with an estimate of the encoded length,
+/// including line breaks. The estimate errs on the side of over-counting.
+pub fn get_encoding_type_and_len(
+    input: &[u8],
+    is_inline: bool,
+    is_body: bool,
+) -> (EncodingType, usize) {
     let base64_len = (input.len() * 4 / 3 + 3) & !3;
     let mut qp_len = if !is_inline { input.len() / 76 } else { 0 };
     let mut is_ascii = true;
     let mut needs_encoding = false;
     let mut line_len = 0;
     let mut prev_ch = 0;
+    let mut bare_eol = 0;
 
     for (pos, &ch) in input.iter().enumerate() {
         line_len += 1;
 
+        // Control characters other than TAB, CR and LF (such as NUL) are
+        // not allowed in 7bit or 8bit data
         if ch >= 127
+            || (ch < b' ' && !matches!(ch, b'\t' | b'\r' | b'\n'))
             || ((ch == b' ' || ch == b'\t')
                 && ((is_body
                     && matches!(input.get(pos + 1..), Some([b'\n', ..] | [b'\r', b'\n', ..])))
@@ -43,10 +86,24 @@ pub fn get_encoding_type(input: &[u8], is_inline: bool, is_body: bool) -> Encodi
             if is_ascii && ch >= 127 {
                 is_ascii = false;
             }
+        } else if ch == b'\r' && is_body && input.get(pos + 1) != Some(&b'\n') {
+            qp_len += 3;
+            bare_eol += 1;
+        } else if ch == b'\r' && !is_body && input.get(pos + 1) != Some(&b'\n') {
+            // Bare CRs are not allowed in 7bit data
+            qp_len += 3;
+            if !needs_encoding {
+                needs_encoding = true;
+            }
         } else if ch == b'='
             || (!is_body && ch == b'\r')
             || (is_inline && (ch == b'\t' || ch == b'\r' || ch == b'\n' || ch == b'?'))
         {
+            // Line breaks in header values must be encoded to prevent
+            // header injection
+            if is_inline && (ch == b'\r' || ch == b'\n') {
+                needs_encoding = true;
+            }
             qp_len += 3;
         } else if ch == b'\n' {
             if !needs_encoding && line_len > 997 {
@@ -55,6 +112,7 @@ pub fn get_encoding_type(input: &[u8], is_inline: bool, is_body: bool) -> Encodi
             if is_body {
                 if prev_ch != b'\r' {
                     qp_len += 1;
+                    bare_eol += 1;
                 }
                 qp_len += 1;
             } else {
@@ -71,50 +129,124 @@ pub fn get_encoding_type(input: &[u8], is_inline: bool, is_body: bool) -> Encodi
         prev_ch = ch;
     }
 
+    // The last line of a part may not end with a line break, header values
+    // are folded instead
+    if !needs_encoding && !is_inline && line_len > 997 {
+        needs_encoding = true;
+    }
+
     if !needs_encoding {
-        EncodingType::None
+        (EncodingType::None, input.len() + bare_eol)
     } else if qp_len < base64_len {
-        EncodingType::QuotedPrintable(is_ascii)
+        (
+            EncodingType::QuotedPrintable(is_ascii),
+            qp_len + if !is_inline { 2 * (qp_len / 76 + 1) } else { 0 },
+        )
     } else {
-        EncodingType::Base64
+        (
+            EncodingType::Base64,
+            base64_encoded_len(input.len(), is_inline),
+        )
+    }
+}
+
+/// Converts bare CR and bare LF line endings to CRLF, borrowing the input
+/// when it is already canonical.
+pub fn normalize_line_endings(input: &[u8]) -> Cow<'_, [u8]> {
+    let is_canonical = input.iter().enumerate().all(|(pos, &ch)| match ch {
+        b'\r' => input.get(pos + 1) == Some(&b'\n'),
+        b'\n' => pos > 0 && input[pos - 1] == b'\r',
+        _ => true,
+    });
+    if is_canonical {
+        return Cow::Borrowed(input);
+    }
+
+    let mut output = Vec::with_capacity(input.len() + 16);
+    for (pos, &ch) in input.iter().enumerate() {
+        match ch {
+            b'\r' => output.extend_from_slice(b"\r\n"),
+            b'\n' if pos > 0 && input[pos - 1] == b'\r' => (),
+            b'\n' => output.extend_from_slice(b"\r\n"),
+            _ => output.push(ch),
+        }
     }
+    Cow::Owned(output)
 }
 
 pub fn rfc2047_encode(input: &str, mut output: impl Write) -> io::Result<usize> {
     Ok(match get_encoding_type(input.as_bytes(), true, false) {
+        EncodingType::None => quoted_string_encode(input, output)?,
+        encoding => {
+            output.write_all(b"\"")?;
+            let bytes_written = encoded_word_encode(input, encoding, &mut output)? + 2;
+            output.write_all(b"\"")?;
+            bytes_written
+        }
+    })
+}
+
+/// Writes the input as an RFC 5322 phrase, such as a display name: a
+/// quoted-string when no encoding is needed, otherwise a bare RFC 2047
+/// encoded-word, as encoded-words inside a quoted-string are not decoded
+/// (RFC 2047, Section 5). Returns the number of bytes written.
+pub fn rfc2047_phrase_encode(input: &str, output: impl Write) -> io::Result<usize> {
+    match get_encoding_type(input.as_bytes(), true, false) {
+        EncodingType::None => quoted_string_encode(input, output),
+        encoding => encoded_word_encode(input, encoding, output),
+    }
+}
+
+fn encoded_word_encode(
+    input: &str,
+    encoding: EncodingType,
+    mut output: impl Write,
+) -> io::Result<usize> {
+    Ok(match encoding {
         EncodingType::Base64 => {
-            output.write_all(b"\"=?utf-8?B?")?;
-            let bytes_written = base64_encode_mime(input.as_bytes(), &mut output, true)? + 14;
-            output.write_all(b"?=\"")?;
+            output.write_all(b"=?utf-8?B?")?;
+            let bytes_written = base64_encode_mime(input.as_bytes(), &mut output, true)? + 12;
+            output.write_all(b"?=")?;
             bytes_written
         }
         EncodingType::QuotedPrintable(is_ascii) => {
             if !is_ascii {
-                output.write_all(b"\"=?utf-8?Q?")?;
+                output.write_all(b"=?utf-8?Q?")?;
             } else {
-                output.write_all(b"\"=?us-ascii?Q?")?;
+                output.write_all(b"=?us-ascii?Q?")?;
             }
             let bytes_written =
-                quoted_printable_encode(input.as_bytes(), &mut output, true, false)?
-                    + if is_ascii { 19 } else { 14 };
-            output.write_all(b"?=\"")?;
-            bytes_written
-        }
-        EncodingType::None => {
-            let mut bytes_written = 2;
-            output.write_all(b"\"")?;
-            for &ch in input.as_bytes() {
-                if ch == b'\\' || ch == b'"' {
-                    output.write_all(b"\\")?;
-                    bytes_written += 1;
-                } else if ch == b'\r' || ch == b'\n' {
-                    continue;
-                }
-                output.write_all(&[ch])?;
-                bytes_written += 1;
-            }
-            output.write_all(b"\"")?;
+                quoted_printable_encode(input.as_bytes(), &mut output, QpMode::Inline)?
+                    + if is_ascii { 15 } else { 12 };
+            output.write_all(b"?=")?;
             bytes_written
         }
+        EncodingType::None => quoted_string_encode(input, output)?,
     })
 }
+
+/// Writes the input as an RFC 5322 quoted-string, escaping backslashes and
+/// double quotes and dropping line breaks. Returns the number of bytes written.
+pub fn quoted_string_encode(input: &str, mut output: impl Write) -> io::Result<usize> {
+    let mut bytes_written = 2;
+    output.write_all(b"\"")?;
+    for &ch in input.as_bytes() {
+        if ch == b'\\' || ch == b'"' {
+            output.write_all(b"\\")?;
+            bytes_written += 1;
+        } else if ch == b'\r' || ch == b'\n' {
+            continue;
+        }
+        output.write_all(&[ch])?;
+        bytes_written += 1;
+    }
+    output.write_all(b"\"")?;
+    Ok(bytes_written)
+}
+
+/// Returns `true` if the input contains non-ASCII characters that may be
+/// written as raw UTF-8 in a header (RFC 6532), that is, it has no control
+/// characters other than tab.
+pub fn is_raw_utf8_header(input: &str) -> bool {
+    !input.is_ascii() && !input.chars().any(|ch| ch.is_control() && ch != '\t')
+}
@@ -0,0 +1,132 @@
+/*
+ * Copyright Stalwart Labs Ltd. See the COPYING
+ * file at the top-level directory of this distribution.
+ *
+ * Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+ * https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+ * <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+ * option. This file may not be copied, modified, or distributed
+ * except according to those terms.
+ */
+
+use crate::{
+    io::{self, Write},
+    LineEnding,
+};
+
+/// Writer that converts the CRLF line endings written to it to the
+/// selected line ending before forwarding them to an inner writer.
+/// Bare CR and LF characters are forwarded unchanged.
+pub struct LineEndingWriter<W: Write> {
+    inner: W,
+    pub(crate) line_ending: LineEnding,
+    pending_cr: bool,
+}
+
+impl<W: Write> LineEndingWriter<W> {
+    /// Wrap a writer.
+    pub fn new(inner: W, line_ending: LineEnding) -> Self {
+        Self {
+            inner,
+            line_ending,
+            pending_cr: false,
+        }
+    }
+
+    /// Returns a reference to the inner writer.
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    /// Set the line ending used for the bytes written from now on, writing
+    /// any CR held back at the end of the last write.
+    pub(crate) fn set_line_ending(&mut self, line_ending: LineEnding) -> io::Result<()> {
+        if self.pending_cr {
+            self.pending_cr = false;
+            self.inner.write_all(b"\r")?;
+        }
+        self.line_ending = line_ending;
+        Ok(())
+    }
+
+    /// Write any CR held back at the end of the last write and unwrap the
+    /// inner writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        if self.pending_cr {
+            self.inner.write_all(b"\r")?;
+        }
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for LineEndingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        if self.line_ending == LineEnding::CrLf {
+            return self.inner.write_all(buf);
+        }
+
+        let mut buf = buf;
+        if self.pending_cr && !buf.is_empty() {
+            self.pending_cr = false;
+            if buf[0] != b'\n' {
+                self.inner.write_all(b"\r")?;
+            }
+        }
+
+        // A CR at the end of the buffer may be followed by a LF in the next
+        // write, so it is held back until then.
+        if let [rest @ .., b'\r'] = buf {
+            self.pending_cr = true;
+            buf = rest;
+        }
+
+        let mut start = 0;
+        for pos in 0..buf.len() {
+            if buf[pos] == b'\r' && buf.get(pos + 1) == Some(&b'\n') {
+                self.inner.write_all(&buf[start..pos])?;
+                start = pos + 1;
+            }
+        }
+        self.inner.write_all(&buf[start..])
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{string::String, vec::Vec};
+
+    use crate::{encoders::LineEndingWriter, io::Write, LineEnding};
+
+    #[test]
+    fn convert_line_endings() {
+        for (chunks, expected_crlf, expected_lf) in [
+            (&["a\r\nb\r\n"][..], "a\r\nb\r\n", "a\nb\n"),
+            (&["a\r", "\nb\r", "\n"], "a\r\nb\r\n", "a\nb\n"),
+            (&["a\rb\nc\r"], "a\rb\nc\r", "a\rb\nc\r"),
+            (&["a\r", "b\r", "", "\n"], "a\rb\r\n", "a\rb\n"),
+        ] {
+            for (line_ending, expected) in [
+                (LineEnding::CrLf, expected_crlf),
+                (LineEnding::Lf, expected_lf),
+            ] {
+                let mut writer = LineEndingWriter::new(Vec::new(), line_ending);
+                for chunk in chunks {
+                    writer.write_all(chunk.as_bytes()).unwrap();
+                }
+                assert_eq!(
+                    String::from_utf8(writer.finish().unwrap()).unwrap(),
+                    expected
+                );
+            }
+        }
+    }
+}
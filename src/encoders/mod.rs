@@ -10,5 +10,12 @@
  */
 
 pub mod base64;
+pub mod counting;
 pub mod encode;
+pub mod line_ending;
 pub mod quoted_printable;
+
+pub use counting::CountingWriter;
+pub use encode::{get_encoding_type, EncodingType};
+pub use line_ending::LineEndingWriter;
+pub use quoted_printable::QpMode;
@@ -9,41 +9,71 @@
  * except according to those terms.
  */
 
-use std::io::{self, Write};
+use crate::io::{self, Write};
+
+const HEX: &[u8; 16] = b"0123456789ABCDEF";
+
+/// Maximum number of characters written before a soft line break, which
+/// leaves room for the `=` within the 76 character limit of RFC 2045.
+const MAX_QP_LINE: usize = 75;
+
+/// Returns whether a byte is a control character other than TAB, CR and
+/// LF, which must always be escaped.
+#[inline]
+fn is_control(ch: u8) -> bool {
+    ch < b' ' && !matches!(ch, b'\t' | b'\r' | b'\n')
+}
+
+/// Returns the `=XX` escape of a byte.
+#[inline]
+fn escape(ch: u8) -> [u8; 3] {
+    [b'=', HEX[(ch >> 4) as usize], HEX[(ch & 0x0f) as usize]]
+}
+
+/// How `quoted_printable_encode` treats line breaks and whitespace.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QpMode {
+    /// Text body: line breaks are written as CRLF hard breaks and
+    /// whitespace before them is escaped.
+    Body,
+    /// Attachment: the contents are preserved exactly, so CR and LF
+    /// are escaped as `=0D` and `=0A`.
+    Attachment,
+    /// RFC 2047 "Q" encoding for header encoded-words, without soft
+    /// line breaks.
+    Inline,
+}
 
 pub fn quoted_printable_encode(
     input: &[u8],
     mut output: impl Write,
-    is_inline: bool,
-    is_body: bool,
+    mode: QpMode,
 ) -> io::Result<usize> {
     let mut bytes_written = 0;
-    if !is_inline {
-        if is_body {
-            let mut prev_ch = 0;
+    match mode {
+        QpMode::Body => {
             for (pos, &ch) in input.iter().enumerate() {
-                if ch == b'='
+                if ch == b'\r' && input.get(pos + 1) == Some(&b'\n') {
+                    continue;
+                } else if ch == b'\n' {
+                    output.write_all(b"\r\n")?;
+                    bytes_written = 0;
+                } else if ch == b'='
+                    || ch == b'\r'
                     || ch >= 127
+                    || is_control(ch)
                     || ((ch == b' ' || ch == b'\t')
                         && (matches!(input.get(pos + 1..), Some([b'\n', ..] | [b'\r', b'\n', ..]))
                             || (pos == input.len() - 1)))
                 {
-                    if bytes_written + 3 > 76 {
+                    if bytes_written + escaped_len(input, pos) > MAX_QP_LINE {
                         output.write_all(b"=\r\n")?;
                         bytes_written = 0;
                     }
-                    output.write_all(format!("={:02X}", ch).as_bytes())?;
+                    output.write_all(&escape(ch))?;
                     bytes_written += 3;
-                } else if ch == b'\n' {
-                    if prev_ch != b'\r' {
-                        output.write_all(b"\r\n")?;
-                    } else {
-                        output.write_all(b"\n")?;
-                    }
-                    bytes_written = 0;
                 } else {
-                    prev_ch = ch;
-                    if bytes_written + 1 > 76 {
+                    if bytes_written + 1 > MAX_QP_LINE {
                         output.write_all(b"=\r\n")?;
                         bytes_written = 0;
                     }
@@ -51,21 +81,23 @@ pub fn quoted_printable_encode(
                     bytes_written += 1;
                 }
             }
-        } else {
+        }
+        QpMode::Attachment => {
             for (pos, &ch) in input.iter().enumerate() {
                 if ch == b'='
                     || ch >= 127
                     || (ch == b'\r' || ch == b'\n')
+                    || is_control(ch)
                     || ((ch == b' ' || ch == b'\t') && (pos == input.len() - 1))
                 {
-                    if bytes_written + 3 > 76 {
+                    if bytes_written + escaped_len(input, pos) > MAX_QP_LINE {
                         output.write_all(b"=\r\n")?;
                         bytes_written = 0;
                     }
-                    output.write_all(format!("={:02X}", ch).as_bytes())?;
+                    output.write_all(&escape(ch))?;
                     bytes_written += 3;
                 } else {
-                    if bytes_written + 1 > 76 {
+                    if bytes_written + 1 > MAX_QP_LINE {
                         output.write_all(b"=\r\n")?;
                         bytes_written = 0;
                     }
@@ -74,17 +106,18 @@ pub fn quoted_printable_encode(
                 }
             }
         }
-    } else {
-        for &ch in input.iter() {
-            if ch == b'=' || ch == b'?' || ch == b'\t' || ch == b'\r' || ch == b'\n' || ch >= 127 {
-                output.write_all(format!("={:02X}", ch).as_bytes())?;
-                bytes_written += 3;
-            } else if ch == b' ' {
-                output.write_all(b"_")?;
-                bytes_written += 1;
-            } else {
-                output.write_all(&[ch])?;
-                bytes_written += 1;
+        QpMode::Inline => {
+            for &ch in input.iter() {
+                if ch == b'=' || ch == b'?' || !(b' '..127).contains(&ch) {
+                    output.write_all(&escape(ch))?;
+                    bytes_written += 3;
+                } else if ch == b' ' {
+                    output.write_all(b"_")?;
+                    bytes_written += 1;
+                } else {
+                    output.write_all(&[ch])?;
+                    bytes_written += 1;
+                }
             }
         }
     }
@@ -92,8 +125,46 @@ pub fn quoted_printable_encode(
     Ok(bytes_written)
 }
 
+/// Returns the length of the escaped sequence starting at `pos`, which
+/// covers all the bytes of a multi-byte UTF-8 character so that they are
+/// kept on the same line.
+fn escaped_len(input: &[u8], pos: usize) -> usize {
+    let char_len = match input[pos] {
+        0xC0..=0xDF => 2,
+        0xE0..=0xEF => 3,
+        0xF0..=0xF7 => 4,
+        _ => return 3,
+    };
+    3 * (1 + input[pos + 1..]
+        .iter()
+        .take(char_len - 1)
+        .take_while(|&&ch| (0x80..0xC0).contains(&ch))
+        .count())
+}
+
+/// Returns the length of the inline (RFC 2047 "Q") encoding of `input`.
+pub fn inline_quoted_printable_len(input: &[u8]) -> usize {
+    input
+        .iter()
+        .map(|&ch| {
+            if ch == b'=' || ch == b'?' || !(b' '..127).contains(&ch) {
+                3
+            } else {
+                1
+            }
+        })
+        .sum()
+}
+
 #[cfg(test)]
 mod tests {
+    use alloc::{
+        format,
+        string::{String, ToString},
+        vec::Vec,
+    };
+
+    use super::QpMode;
 
     #[test]
     fn encode_quoted_printable() {
@@ -134,6 +205,12 @@ mod tests {
                 "hello   =0Aworld   =0A",
                 "hello___=0Aworld___=0A",
             ),
+            (
+                "a\nb\r\nc\rd".to_string(),
+                "a\r\nb\r\nc=0Dd",
+                "a=0Ab=0D=0Ac=0Dd",
+                "a=0Ab=0D=0Ac=0Dd",
+            ),
             (
                 "áéíóú".to_string(),
                 "=C3=A1=C3=A9=C3=AD=C3=B3=C3=BA",
@@ -150,13 +227,13 @@ mod tests {
                 " ".repeat(100),
                 concat!(
                     "                                            ",
-                    "                                =\r\n    ",
-                    "                   =20"
+                    "                               =\r\n    ",
+                    "                    =20"
                 ),
                 concat!(
                     "                                            ",
-                    "                                =\r\n    ",
-                    "                   =20"
+                    "                               =\r\n    ",
+                    "                    =20"
                 ),
                 concat!(
                     "_________________________________________",
@@ -166,28 +243,126 @@ mod tests {
             ),
         ] {
             let mut output = Vec::new();
-            super::quoted_printable_encode(input.as_bytes(), &mut output, false, true).unwrap();
+            super::quoted_printable_encode(input.as_bytes(), &mut output, QpMode::Body).unwrap();
             assert_eq!(
-                std::str::from_utf8(&output).unwrap(),
+                core::str::from_utf8(&output).unwrap(),
                 expected_result_body,
                 "body"
             );
 
             let mut output = Vec::new();
-            super::quoted_printable_encode(input.as_bytes(), &mut output, false, false).unwrap();
+            super::quoted_printable_encode(input.as_bytes(), &mut output, QpMode::Attachment)
+                .unwrap();
             assert_eq!(
-                std::str::from_utf8(&output).unwrap(),
+                core::str::from_utf8(&output).unwrap(),
                 expected_result_attachment,
                 "attachment"
             );
 
             let mut output = Vec::new();
-            super::quoted_printable_encode(input.as_bytes(), &mut output, true, false).unwrap();
+            super::quoted_printable_encode(input.as_bytes(), &mut output, QpMode::Inline).unwrap();
             assert_eq!(
-                std::str::from_utf8(&output).unwrap(),
+                core::str::from_utf8(&output).unwrap(),
                 expected_result_inline,
                 "inline"
             );
         }
     }
+
+    #[test]
+    fn encode_quoted_printable_keep_chars() {
+        for len in [8, 9, 10, 20] {
+            let input = format!("a{}", "ハ".repeat(len));
+            for mode in [QpMode::Body, QpMode::Attachment] {
+                let mut output = Vec::new();
+                super::quoted_printable_encode(input.as_bytes(), &mut output, mode).unwrap();
+                let output = String::from_utf8(output).unwrap();
+                let lines = output.split("=\r\n").collect::<Vec<_>>();
+
+                assert_eq!(lines.concat(), format!("a{}", "=E3=83=8F".repeat(len)));
+                assert_eq!(lines[0].len(), 73);
+                for line in &lines[1..] {
+                    assert!(line.len() <= 76 && line.len() % 9 == 0, "{output}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn encode_quoted_printable_random() {
+        // Xorshift, biased towards characters that are escaped or wrapped
+        let mut seed = 0x2545_f491_4f6c_dd1du64;
+        let alphabet = b"a =\t\r\n\x00\x7f\xc3\xa1\xe3\x83\x8f";
+        for _ in 0..500 {
+            let mut input = Vec::new();
+            for _ in 0..(seed % 400) {
+                seed ^= seed << 13;
+                seed ^= seed >> 7;
+                seed ^= seed << 17;
+                input.push(if seed.is_multiple_of(3) {
+                    (seed >> 8) as u8
+                } else {
+                    alphabet[(seed >> 8) as usize % alphabet.len()]
+                });
+            }
+
+            for mode in [QpMode::Body, QpMode::Attachment] {
+                let mut output = Vec::new();
+                super::quoted_printable_encode(&input, &mut output, mode).unwrap();
+                let lines = output.split(|&ch| ch == b'\n').collect::<Vec<_>>();
+
+                for (num, line) in lines.iter().enumerate() {
+                    let is_last = num == lines.len() - 1;
+                    let line = if is_last {
+                        *line
+                    } else {
+                        line.strip_suffix(b"\r").expect("bare LF")
+                    };
+                    assert!(line.len() <= 76, "{line:?}");
+
+                    // Every `=` is either an escape or a soft line break
+                    let mut pos = 0;
+                    while pos < line.len() {
+                        if line[pos] == b'=' {
+                            if pos == line.len() - 1 {
+                                assert!(!is_last, "trailing '=' in {input:?}");
+                            } else {
+                                assert!(
+                                    line[pos + 1..]
+                                        .iter()
+                                        .take(2)
+                                        .filter(|ch| b"0123456789ABCDEF".contains(ch))
+                                        .count()
+                                        == 2,
+                                    "incomplete escape in {input:?}"
+                                );
+                                pos += 2;
+                            }
+                        } else {
+                            assert!(
+                                line[pos] == b'\t' || (b' '..127).contains(&line[pos]),
+                                "control character in {input:?}"
+                            );
+                        }
+                        pos += 1;
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn encode_quoted_printable_modes() {
+        // Bodies keep their line breaks, attachments preserve every octet
+        let input = b"line one \nline two\r\n\rend";
+        for (mode, expected) in [
+            (QpMode::Body, "line one=20\r\nline two\r\n=0Dend"),
+            (QpMode::Attachment, "line one =0Aline two=0D=0A=0Dend"),
+            (QpMode::Inline, "line_one_=0Aline_two=0D=0A=0Dend"),
+        ] {
+            let mut output = Vec::new();
+            super::quoted_printable_encode(input, &mut output, mode).unwrap();
+            assert_eq!(core::str::from_utf8(&output).unwrap(), expected, "{mode:?}");
+        }
+    }
 }
@@ -9,11 +9,14 @@
  * except according to those terms.
  */
 
-use std::borrow::Cow;
+use alloc::{borrow::Cow, string::String, vec, vec::Vec};
 
-use crate::encoders::encode::rfc2047_encode;
+use crate::{
+    encoders::encode::{is_raw_utf8_header, quoted_string_encode, rfc2047_phrase_encode},
+    io, is_valid_addr_spec, WriteOptions,
+};
 
-use super::Header;
+use super::{write_fold, Header, MAX_FOLD_LENGTH};
 
 /// RFC5322 e-mail address
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
@@ -62,6 +65,39 @@ impl<'x> Address<'x> {
         Address::List(items)
     }
 
+    /// Returns the number of mailboxes, including those inside groups.
+    pub fn mailbox_count(&self) -> usize {
+        match self {
+            Address::Address(_) => 1,
+            Address::Group(group) => group.addresses.iter().map(|a| a.mailbox_count()).sum(),
+            Address::List(list) => list.iter().map(|a| a.mailbox_count()).sum(),
+        }
+    }
+
+    /// Returns the first mailbox, including those inside groups.
+    pub fn first_mailbox(&self) -> Option<&EmailAddress<'x>> {
+        match self {
+            Address::Address(address) => Some(address),
+            Address::Group(group) => group.addresses.iter().find_map(|a| a.first_mailbox()),
+            Address::List(list) => list.iter().find_map(|a| a.first_mailbox()),
+        }
+    }
+
+    /// Append the addresses of `other`, turning this address into a list
+    /// when needed.
+    pub fn append(&mut self, other: Address<'x>) {
+        if !matches!(self, Address::List(_)) {
+            let current = core::mem::replace(self, Address::List(Vec::new()));
+            *self = Address::List(vec![current]);
+        }
+        if let Address::List(list) = self {
+            match other {
+                Address::List(other) => list.extend(other),
+                other => list.push(other),
+            }
+        }
+    }
+
     pub fn unwrap_address(&self) -> &EmailAddress<'x> {
         match self {
             Address::Address(address) => address,
@@ -128,51 +164,113 @@ where
     }
 }
 
+fn write_display_name(
+    name: &str,
+    output: impl io::Write,
+    options: &WriteOptions,
+) -> io::Result<usize> {
+    if options.utf8_headers && is_raw_utf8_header(name) {
+        quoted_string_encode(name, output)
+    } else {
+        rfc2047_phrase_encode(name, output)
+    }
+}
+
+/// Returns the length of a display name once encoded.
+fn display_name_len(name: &str, options: &WriteOptions) -> io::Result<usize> {
+    write_display_name(name, &mut Vec::new(), options)
+}
+
+/// Returns the length of a group name once written, excluding the colon.
+fn group_name_len(name: &str, options: &WriteOptions) -> io::Result<usize> {
+    if is_atom(name) {
+        Ok(name.len())
+    } else {
+        display_name_len(name, options)
+    }
+}
+
+impl EmailAddress<'_> {
+    /// Returns the length of the address once written, including the
+    /// display name.
+    fn written_len(&self, options: &WriteOptions) -> io::Result<usize> {
+        Ok(match self.name.as_ref().filter(|name| !name.is_empty()) {
+            Some(name) => display_name_len(name, options)? + 1,
+            None => 0,
+        } + self.email.len()
+            + 2)
+    }
+}
+
+fn is_atom(value: &str) -> bool {
+    !value.is_empty()
+        && value
+            .bytes()
+            .all(|ch| ch.is_ascii_alphanumeric() || b"!#$%&'*+-/=?^_`{|}~".contains(&ch))
+}
+
 impl<'x> Header for Address<'x> {
-    fn write_header(
+    fn write_header(&self, output: impl io::Write, bytes_written: usize) -> io::Result<usize> {
+        self.write_header_with(output, bytes_written, &WriteOptions::default())
+    }
+
+    fn is_empty(&self) -> bool {
+        matches!(self, Address::List(list) if list.iter().all(|address| address.is_empty()))
+    }
+
+    fn write_header_with(
         &self,
-        mut output: impl std::io::Write,
+        mut output: impl io::Write,
         mut bytes_written: usize,
-    ) -> std::io::Result<usize> {
+        options: &WriteOptions,
+    ) -> io::Result<usize> {
+        if self.is_empty() {
+            return Ok(bytes_written);
+        }
+
         match self {
             Address::Address(address) => {
-                address.write_header(&mut output, bytes_written)?;
+                address.write_header_with(&mut output, bytes_written, options)?;
             }
             Address::Group(group) => {
-                group.write_header(&mut output, bytes_written)?;
+                group.write_header_with(&mut output, bytes_written, options)?;
             }
             Address::List(list) => {
                 for (pos, address) in list.iter().enumerate() {
-                    if bytes_written
-                        + (match address {
-                            Address::Address(address) => {
-                                address.email.len()
-                                    + address.name.as_ref().map_or(0, |n| n.len() + 3)
-                                    + 2
-                            }
-                            Address::Group(group) => {
-                                group.name.as_ref().map_or(0, |name| name.len() + 2)
-                            }
-                            Address::List(_) => 0,
-                        })
-                        >= 76
-                    {
-                        output.write_all(b"\r\n\t")?;
-                        bytes_written = 1;
+                    // The address, or the group name, and the `,` that follows it
+                    let len = match address {
+                        Address::Address(address) => address.written_len(options)?,
+                        Address::Group(group) => match &group.name {
+                            Some(name) => group_name_len(name, options)? + 1,
+                            None => 0,
+                        },
+                        Address::List(_) => 0,
+                    } + usize::from(pos < list.len() - 1);
+                    if pos > 0 && bytes_written + 1 + len > MAX_FOLD_LENGTH {
+                        bytes_written = write_fold(&mut output, options)?;
+                    } else if pos > 0 {
+                        output.write_all(b" ")?;
+                        bytes_written += 1;
                     }
 
                     match address {
                         Address::Address(address) => {
-                            bytes_written += address.write_header(&mut output, bytes_written)?;
+                            bytes_written = address.write_address(
+                                &mut output,
+                                bytes_written,
+                                options,
+                                usize::from(pos < list.len() - 1),
+                            )?;
                             if pos < list.len() - 1 {
-                                output.write_all(b", ")?;
+                                output.write_all(b",")?;
                                 bytes_written += 1;
                             }
                         }
                         Address::Group(group) => {
-                            bytes_written += group.write_header(&mut output, bytes_written)?;
+                            bytes_written =
+                                group.write_header_with(&mut output, bytes_written, options)?;
                             if pos < list.len() - 1 {
-                                output.write_all(b"; ")?;
+                                output.write_all(b",")?;
                                 bytes_written += 1;
                             }
                         }
@@ -186,17 +284,59 @@ impl<'x> Header for Address<'x> {
     }
 }
 
+impl<'x> EmailAddress<'x> {
+    /// Write only the addr-spec, without the display name or angle
+    /// brackets, as used in trace headers and envelope logging. Returns
+    /// an error if the address is not a syntactically valid addr-spec.
+    pub fn write_bare(&self, mut output: impl io::Write) -> io::Result<usize> {
+        if !is_valid_addr_spec(&self.email) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid e-mail address",
+            ));
+        }
+        output.write_all(self.email.as_bytes())?;
+        Ok(self.email.len())
+    }
+}
+
 impl<'x> Header for EmailAddress<'x> {
-    fn write_header(
+    fn write_header(&self, output: impl io::Write, bytes_written: usize) -> io::Result<usize> {
+        self.write_header_with(output, bytes_written, &WriteOptions::default())
+    }
+
+    fn write_header_with(
         &self,
-        mut output: impl std::io::Write,
+        output: impl io::Write,
+        bytes_written: usize,
+        options: &WriteOptions,
+    ) -> io::Result<usize> {
+        self.write_address(output, bytes_written, options, 0)
+    }
+}
+
+impl EmailAddress<'_> {
+    /// Writes the address, folding between the display name and the
+    /// addr-spec when the line, including the `delimiter_len` characters
+    /// written after the address, would be too long.
+    fn write_address(
+        &self,
+        mut output: impl io::Write,
         mut bytes_written: usize,
-    ) -> std::io::Result<usize> {
-        if let Some(name) = &self.name {
-            bytes_written += rfc2047_encode(name, &mut output)?;
-            if bytes_written + self.email.len() + 2 >= 76 {
-                output.write_all(b"\r\n\t")?;
-                bytes_written = 1;
+        options: &WriteOptions,
+        delimiter_len: usize,
+    ) -> io::Result<usize> {
+        if self.email.contains(['\r', '\n']) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "e-mail address contains line breaks",
+            ));
+        }
+
+        if let Some(name) = self.name.as_ref().filter(|name| !name.is_empty()) {
+            bytes_written += write_display_name(name, &mut output, options)?;
+            if bytes_written + 1 + self.email.len() + 2 + delimiter_len > MAX_FOLD_LENGTH {
+                bytes_written = write_fold(&mut output, options)?;
             } else {
                 output.write_all(b" ")?;
                 bytes_written += 1;
@@ -212,36 +352,168 @@ impl<'x> Header for EmailAddress<'x> {
 }
 
 impl<'x> Header for GroupedAddresses<'x> {
-    fn write_header(
+    fn write_header(&self, output: impl io::Write, bytes_written: usize) -> io::Result<usize> {
+        self.write_header_with(output, bytes_written, &WriteOptions::default())
+    }
+
+    fn write_header_with(
         &self,
-        mut output: impl std::io::Write,
+        mut output: impl io::Write,
         mut bytes_written: usize,
-    ) -> std::io::Result<usize> {
+        options: &WriteOptions,
+    ) -> io::Result<usize> {
         if let Some(name) = &self.name {
-            bytes_written += rfc2047_encode(name, &mut output)? + 2;
-            output.write_all(b": ")?;
+            // Group names made of a single atom, such as
+            // "undisclosed-recipients", are conventionally left unquoted
+            bytes_written += if is_atom(name) {
+                output.write_all(name.as_bytes())?;
+                name.len()
+            } else {
+                write_display_name(name, &mut output, options)?
+            } + 1;
+            output.write_all(b":")?;
         }
 
         for (pos, address) in self.addresses.iter().enumerate() {
             let address = address.unwrap_address();
 
-            if bytes_written
-                + address.email.len()
-                + address.name.as_ref().map_or(0, |n| n.len() + 3)
-                + 2
-                >= 76
-            {
-                output.write_all(b"\r\n\t")?;
-                bytes_written = 1;
+            // The address and the `,` or `;` that follows it
+            let has_separator = pos > 0 || self.name.is_some();
+            let len = address.written_len(options)?
+                + usize::from(pos < self.addresses.len() - 1 || self.name.is_some());
+            if has_separator && bytes_written + 1 + len > MAX_FOLD_LENGTH {
+                bytes_written = write_fold(&mut output, options)?;
+            } else if has_separator {
+                output.write_all(b" ")?;
+                bytes_written += 1;
             }
 
-            bytes_written += address.write_header(&mut output, bytes_written)?;
+            bytes_written = address.write_address(
+                &mut output,
+                bytes_written,
+                options,
+                usize::from(pos < self.addresses.len() - 1 || self.name.is_some()),
+            )?;
             if pos < self.addresses.len() - 1 {
-                output.write_all(b", ")?;
-                bytes_written += 2;
+                output.write_all(b",")?;
+                bytes_written += 1;
             }
         }
 
+        // Groups are always terminated, even when empty
+        if self.name.is_some() {
+            output.write_all(b";")?;
+            bytes_written += 1;
+        }
+
         Ok(bytes_written)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use alloc::{string::String, vec, vec::Vec};
+
+    use crate::headers::{address::Address, Header};
+
+    #[test]
+    fn write_bare_address() {
+        for (address, expected) in [
+            (
+                Address::from("bounces@example.com"),
+                "<bounces@example.com>\r\n",
+            ),
+            (
+                Address::new_address(Some(""), "bounces@example.com"),
+                "<bounces@example.com>\r\n",
+            ),
+            (
+                Address::new_address(
+                    None::<&str>,
+                    "a-rather-long-address@a-rather-long-domain-name.example.com",
+                ),
+                "<a-rather-long-address@a-rather-long-domain-name.example.com>\r\n",
+            ),
+        ] {
+            let mut output = Vec::new();
+            address
+                .write_header(&mut output, "Return-Path: ".len())
+                .unwrap();
+            assert_eq!(String::from_utf8(output).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn write_bare_addr_spec() {
+        let address = Address::from(("John Doe", "john@doe.com"));
+        let mut output = Vec::new();
+        assert_eq!(
+            address.unwrap_address().write_bare(&mut output).unwrap(),
+            12
+        );
+        assert_eq!(output, b"john@doe.com");
+
+        let mut output = Vec::new();
+        Address::from("\"john doe\"@doe.com")
+            .unwrap_address()
+            .write_bare(&mut output)
+            .unwrap();
+        assert_eq!(output, b"\"john doe\"@doe.com");
+
+        for email in ["john@doe.com\r\nX: y", "john doe@doe.com", "<john@doe.com>"] {
+            assert!(Address::from(email)
+                .unwrap_address()
+                .write_bare(&mut Vec::new())
+                .is_err());
+        }
+    }
+
+    #[test]
+    fn write_groups() {
+        for (address, expected) in [
+            (
+                Address::from(("hidden-recipients", Vec::<(&str, &str)>::new())),
+                "hidden-recipients:;\r\n",
+            ),
+            (
+                Address::from(("Team", vec!["a@doe.com", "b@doe.com"])),
+                "Team: <a@doe.com>, <b@doe.com>;\r\n",
+            ),
+            (
+                Address::from(vec![
+                    Address::from(("undisclosed-recipients", Vec::<&str>::new())),
+                    Address::from("john@doe.com"),
+                    Address::from(("Team", vec!["a@doe.com"])),
+                ]),
+                "undisclosed-recipients:;, <john@doe.com>, Team: <a@doe.com>;\r\n",
+            ),
+            (Address::new_list(vec![]), ""),
+        ] {
+            let mut output = Vec::new();
+            address.write_header(&mut output, "To: ".len()).unwrap();
+            assert_eq!(String::from_utf8(output).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn write_quoted_display_name() {
+        for (address, expected) in [
+            (
+                Address::from(("Doe, John", "john@doe.com")),
+                "\"Doe, John\" <john@doe.com>\r\n",
+            ),
+            (
+                Address::from(("O'Brien (work)", "obrien@example.com")),
+                "\"O'Brien (work)\" <obrien@example.com>\r\n",
+            ),
+            (
+                Address::from(("John \"Johnny\" Doe \\ <x@y.com>", "john@doe.com")),
+                "\"John \\\"Johnny\\\" Doe \\\\ <x@y.com>\" <john@doe.com>\r\n",
+            ),
+        ] {
+            let mut output = Vec::new();
+            address.write_header(&mut output, "From: ".len()).unwrap();
+            assert_eq!(String::from_utf8(output).unwrap(), expected);
+        }
+    }
+}
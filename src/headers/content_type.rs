@@ -9,11 +9,14 @@
  * except according to those terms.
  */
 
-use std::borrow::Cow;
+use alloc::{borrow::Cow, vec::Vec};
 
-use crate::encoders::encode::rfc2047_encode;
+use crate::{
+    encoders::encode::{is_raw_utf8_header, quoted_string_encode, rfc2047_encode},
+    io, WriteOptions,
+};
 
-use super::Header;
+use super::{is_token, write_fold, Header, MAX_FOLD_LENGTH};
 
 /// MIME Content-Type or Content-Disposition header
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
@@ -52,31 +55,62 @@ impl<'x> ContentType<'x> {
     }
 }
 
+/// The type and the attribute names must be RFC 2045 tokens, otherwise
+/// writing fails with an `InvalidInput` error, which prevents header
+/// injection through them.
 impl<'x> Header for ContentType<'x> {
-    fn write_header(
+    fn write_header(&self, output: impl io::Write, bytes_written: usize) -> io::Result<usize> {
+        self.write_header_with(output, bytes_written, &WriteOptions::default())
+    }
+
+    fn write_header_with(
         &self,
-        mut output: impl std::io::Write,
+        mut output: impl io::Write,
         mut bytes_written: usize,
-    ) -> std::io::Result<usize> {
+        options: &WriteOptions,
+    ) -> io::Result<usize> {
+        let is_valid_type = match self.c_type.split_once('/') {
+            Some((top_level, subtype)) => is_token(top_level) && is_token(subtype),
+            None => is_token(&self.c_type),
+        };
+        if !is_valid_type {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid content type",
+            ));
+        }
+        if !self.attributes.iter().all(|(key, _)| is_token(key)) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid content type attribute name",
+            ));
+        }
+
         output.write_all(self.c_type.as_bytes())?;
         bytes_written += self.c_type.len();
-        if !self.attributes.is_empty() {
-            output.write_all(b"; ")?;
-            bytes_written += 2;
-            for (pos, (key, value)) in self.attributes.iter().enumerate() {
-                if bytes_written + key.len() + value.len() + 3 >= 76 {
-                    output.write_all(b"\r\n\t")?;
-                    bytes_written = 1;
-                }
+        let mut encoded = Vec::new();
+        for (pos, (key, value)) in self.attributes.iter().enumerate() {
+            encoded.clear();
+            if options.utf8_headers && is_raw_utf8_header(value) {
+                quoted_string_encode(value, &mut encoded)?;
+            } else {
+                rfc2047_encode(value, &mut encoded)?;
+            }
 
-                output.write_all(key.as_bytes())?;
-                output.write_all(b"=")?;
-                bytes_written += rfc2047_encode(value, &mut output)? + key.len() + 1;
-                if pos < self.attributes.len() - 1 {
-                    output.write_all(b"; ")?;
-                    bytes_written += 2;
-                }
+            // The parameter and the `;` that follows it
+            output.write_all(b";")?;
+            let len = key.len() + 1 + encoded.len() + usize::from(pos < self.attributes.len() - 1);
+            if bytes_written + 2 + len > MAX_FOLD_LENGTH {
+                bytes_written = write_fold(&mut output, options)?;
+            } else {
+                output.write_all(b" ")?;
+                bytes_written += 2;
             }
+
+            output.write_all(key.as_bytes())?;
+            output.write_all(b"=")?;
+            output.write_all(&encoded)?;
+            bytes_written += key.len() + 1 + encoded.len();
         }
         output.write_all(b"\r\n")?;
         Ok(0)
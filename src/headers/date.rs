@@ -9,10 +9,9 @@
  * except according to those terms.
  */
 
-use std::{
-    io::{self, Write},
-    time::SystemTime,
-};
+use alloc::{format, string::String};
+
+use crate::io::{self, Write};
 
 pub static DOW: &[&str] = &["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
 pub static MONTH: &[&str] = &[
@@ -25,58 +24,83 @@ use super::Header;
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Date {
     pub date: i64,
+    offset: i32,
 }
 
 impl Date {
     /// Create a new Date header from a timestamp.
     pub fn new(date: i64) -> Self {
-        Self { date }
+        Self { date, offset: 0 }
+    }
+
+    /// Create a new Date header from a timestamp, rendered in the local
+    /// time of a zone that is `offset` seconds east of UTC at that instant.
+    /// For zoned times, pass the offset in effect at the timestamp so that
+    /// daylight saving time is reflected. Fails with an `InvalidInput`
+    /// error when the offset is not a whole number of minutes or is 24
+    /// hours or more.
+    pub fn with_offset(date: i64, offset: i32) -> io::Result<Self> {
+        if offset % 60 != 0 || offset.unsigned_abs() >= 86400 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid UTC offset",
+            ));
+        }
+        Ok(Self { date, offset })
     }
 
     /// Create a new Date header using the current time.
+    #[cfg(feature = "std")]
     pub fn now() -> Self {
-        Self {
-            date: SystemTime::now()
-                .duration_since(SystemTime::UNIX_EPOCH)
+        Self::new(
+            std::time::SystemTime::now()
+                .duration_since(std::time::SystemTime::UNIX_EPOCH)
                 .map(|d| d.as_secs())
                 .unwrap_or(0) as i64,
-        }
+        )
+    }
+
+    /// Returns the offset from UTC in seconds, positive east of Greenwich.
+    pub fn offset(&self) -> i32 {
+        self.offset
     }
 
     /// Returns an RFC822 date.
     pub fn to_rfc822(&self) -> String {
-        // Ported from http://howardhinnant.github.io/date_algorithms.html#civil_from_days
-        let (z, seconds) = ((self.date / 86400) + 719468, self.date % 86400);
-        let era: i64 = (if z >= 0 { z } else { z - 146096 }) / 146097;
-        let doe: u64 = (z - era * 146097) as u64; // [0, 146096]
-        let yoe: u64 = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365; // [0, 399]
-        let y: i64 = (yoe as i64) + era * 400;
-        let doy: u64 = doe - (365 * yoe + yoe / 4 - yoe / 100); // [0, 365]
-        let mp = (5 * doy + 2) / 153; // [0, 11]
-        let d: u64 = doy - (153 * mp + 2) / 5 + 1; // [1, 31]
-        let m: u64 = if mp < 10 { mp + 3 } else { mp - 9 }; // [1, 12]
-        let (h, mn, s) = (seconds / 3600, (seconds / 60) % 60, seconds % 60);
-
-        format!(
-            "{}, {} {} {:04} {:02}:{:02}:{:02} +0000", //{}{:02}{:02}",
-            DOW[(((self.date as f64 / 86400.0).floor() as i64 + 4).rem_euclid(7)) as usize],
-            d,
-            MONTH.get(m.saturating_sub(1) as usize).unwrap_or(&""),
-            (y + i64::from(m <= 2)),
-            h,
-            mn,
-            s,
-            /*if self.tz_before_gmt && (self.tz_hour > 0 || self.tz_minute > 0) {
-                "-"
-            } else {
-                "+"
-            },
-            self.tz_hour,
-            self.tz_minute*/
-        )
+        to_rfc822(self.date, self.offset)
     }
 }
 
+fn to_rfc822(date: i64, offset: i32) -> String {
+    // Ported from http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let date = date + offset as i64;
+    let (z, seconds) = (date.div_euclid(86400) + 719468, date.rem_euclid(86400));
+    let era: i64 = (if z >= 0 { z } else { z - 146096 }) / 146097;
+    let doe: u64 = (z - era * 146097) as u64; // [0, 146096]
+    let yoe: u64 = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365; // [0, 399]
+    let y: i64 = (yoe as i64) + era * 400;
+    let doy: u64 = doe - (365 * yoe + yoe / 4 - yoe / 100); // [0, 365]
+    let mp = (5 * doy + 2) / 153; // [0, 11]
+    let d: u64 = doy - (153 * mp + 2) / 5 + 1; // [1, 31]
+    let m: u64 = if mp < 10 { mp + 3 } else { mp - 9 }; // [1, 12]
+    let (h, mn, s) = (seconds / 3600, (seconds / 60) % 60, seconds % 60);
+    let offset_minutes = offset.unsigned_abs() / 60;
+
+    format!(
+        "{}, {} {} {:04} {:02}:{:02}:{:02} {}{:02}{:02}",
+        DOW[((date.div_euclid(86400) + 4).rem_euclid(7)) as usize],
+        d,
+        MONTH.get(m.saturating_sub(1) as usize).unwrap_or(&""),
+        (y + i64::from(m <= 2)),
+        h,
+        mn,
+        s,
+        if offset < 0 { "-" } else { "+" },
+        offset_minutes / 60,
+        offset_minutes % 60,
+    )
+}
+
 impl From<i64> for Date {
     fn from(datetime: i64) -> Self {
         Date::new(datetime)
@@ -89,6 +113,22 @@ impl From<u64> for Date {
     }
 }
 
+/// Captures the offset in effect at that instant, so daylight saving time
+/// is reflected. Offsets that are not whole minutes, such as the local mean
+/// time used before standard time zones, are rounded to the nearest minute.
+#[cfg(feature = "chrono-tz")]
+impl From<chrono::DateTime<chrono_tz::Tz>> for Date {
+    fn from(datetime: chrono::DateTime<chrono_tz::Tz>) -> Self {
+        use chrono::Offset;
+
+        let offset = datetime.offset().fix().local_minus_utc();
+        Date {
+            date: datetime.timestamp(),
+            offset: (offset + 30).div_euclid(60) * 60,
+        }
+    }
+}
+
 impl Header for Date {
     fn write_header(&self, mut output: impl Write, _bytes_written: usize) -> io::Result<usize> {
         output.write_all(self.to_rfc822().as_bytes())?;
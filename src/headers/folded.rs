@@ -0,0 +1,101 @@
+/*
+ * Copyright Stalwart Labs Ltd. See the COPYING
+ * file at the top-level directory of this distribution.
+ *
+ * Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+ * https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+ * <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+ * option. This file may not be copied, modified, or distributed
+ * except according to those terms.
+ */
+
+use alloc::borrow::Cow;
+
+use crate::io;
+
+use super::Header;
+
+/// Pre-folded e-mail header, written exactly as given.
+/// Useful to re-emit headers taken from an existing message.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Folded<'x> {
+    pub value: Cow<'x, str>,
+}
+
+impl<'x> Folded<'x> {
+    /// Create a new pre-folded header. Returns an error unless every line
+    /// break is a CRLF followed by a space or tab.
+    pub fn new(value: impl Into<Cow<'x, str>>) -> io::Result<Self> {
+        let value = value.into();
+        if is_valid_folded(&value) {
+            Ok(Self { value })
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "header value contains line breaks that are not folding whitespace",
+            ))
+        }
+    }
+}
+
+/// Returns `true` if the only line breaks in `value` are CRLFs followed by
+/// whitespace, and it has no other control characters besides tab.
+pub fn is_valid_folded(value: &str) -> bool {
+    let bytes = value.as_bytes();
+    bytes.iter().enumerate().all(|(pos, &ch)| match ch {
+        b'\r' => {
+            bytes.get(pos + 1) == Some(&b'\n') && matches!(bytes.get(pos + 2), Some(b' ' | b'\t'))
+        }
+        b'\n' => pos > 0 && bytes[pos - 1] == b'\r',
+        b'\t' => true,
+        ch => !ch.is_ascii_control(),
+    })
+}
+
+impl<'x> Header for Folded<'x> {
+    fn write_header(&self, mut output: impl io::Write, _bytes_written: usize) -> io::Result<usize> {
+        if !is_valid_folded(&self.value) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "header value contains line breaks that are not folding whitespace",
+            ));
+        }
+        output.write_all(self.value.as_bytes())?;
+        output.write_all(b"\r\n")?;
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{format, vec::Vec};
+
+    use crate::headers::{folded::Folded, Header};
+
+    #[test]
+    fn write_folded() {
+        for value in [
+            "simple",
+            "first line\r\n\tsecond line\r\n third line",
+            "a very long value that is not wrapped even if it is longer than seventy-six characters",
+        ] {
+            let mut output = Vec::new();
+            Folded::new(value)
+                .unwrap()
+                .write_header(&mut output, 0)
+                .unwrap();
+            assert_eq!(output, format!("{value}\r\n").as_bytes());
+        }
+
+        for value in [
+            "bare\nline feed",
+            "bare\rcarriage return",
+            "missing\r\nwhitespace",
+            "trailing\r\n",
+            "\n\tleading line feed",
+            "null\0byte",
+        ] {
+            assert!(Folded::new(value).is_err(), "{value:?}");
+        }
+    }
+}
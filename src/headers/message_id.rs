@@ -9,11 +9,11 @@
  * except according to those terms.
  */
 
-use std::borrow::Cow;
+use alloc::{borrow::Cow, string::String, vec, vec::Vec};
 
-use crate::mime::make_boundary;
+use crate::{io, mime::make_boundary, WriteOptions};
 
-use super::Header;
+use super::{write_fold, Header, MAX_FOLD_LENGTH};
 
 /// RFC5322 Message ID header
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
@@ -80,10 +80,34 @@ where
     }
 }
 
+/// Generate a new unique Message ID for the given host name.
+pub fn generate_message_id(hostname: &str) -> String {
+    let mut id = make_boundary(".");
+    id.push('@');
+    id.push_str(hostname);
+    id
+}
+
+/// Returns the host name used for generated Message IDs.
+pub fn default_hostname() -> String {
+    #[cfg(feature = "gethostname")]
+    {
+        gethostname::gethostname()
+            .to_str()
+            .unwrap_or("localhost")
+            .into()
+    }
+
+    #[cfg(not(feature = "gethostname"))]
+    {
+        "localhost".into()
+    }
+}
+
 pub fn generate_message_id_header(
-    mut output: impl std::io::Write,
+    mut output: impl crate::io::Write,
     hostname: &str,
-) -> std::io::Result<()> {
+) -> crate::io::Result<()> {
     output.write_all(b"<")?;
     output.write_all(make_boundary(".").as_bytes())?;
     output.write_all(b"@")?;
@@ -91,17 +115,27 @@ pub fn generate_message_id_header(
     output.write_all(b">")
 }
 
+/// Surrounding whitespace and angle brackets are stripped from each id, as
+/// callers often pass ids copied from a parsed header. Ids that are empty or
+/// contain whitespace, control characters or angle brackets are rejected
+/// with an `InvalidInput` error, which prevents header injection through
+/// Message-ID and Content-ID values.
 impl<'x> Header for MessageId<'x> {
-    fn write_header(
+    fn write_header(&self, output: impl io::Write, bytes_written: usize) -> io::Result<usize> {
+        self.write_header_with(output, bytes_written, &WriteOptions::default())
+    }
+
+    fn write_header_with(
         &self,
-        mut output: impl std::io::Write,
+        mut output: impl io::Write,
         mut bytes_written: usize,
-    ) -> std::io::Result<usize> {
+        options: &WriteOptions,
+    ) -> io::Result<usize> {
         for (pos, id) in self.id.iter().enumerate() {
+            let id = sanitize_id(id)?;
             if pos > 0 {
-                if bytes_written + id.len() + 2 >= 76 {
-                    output.write_all(b"\r\n\t")?;
-                    bytes_written = 1;
+                if bytes_written + 1 + id.len() + 2 > MAX_FOLD_LENGTH {
+                    bytes_written = write_fold(&mut output, options)?;
                 } else {
                     output.write_all(b" ")?;
                     bytes_written += 1;
@@ -121,3 +155,52 @@ impl<'x> Header for MessageId<'x> {
         Ok(0)
     }
 }
+
+fn sanitize_id(id: &str) -> io::Result<&str> {
+    let id = id.trim();
+    let id = id
+        .strip_prefix('<')
+        .and_then(|id| id.strip_suffix('>'))
+        .unwrap_or(id);
+    if id.is_empty()
+        || id
+            .chars()
+            .any(|ch| ch.is_whitespace() || ch.is_control() || ch == '<' || ch == '>')
+    {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "invalid message id",
+        ))
+    } else {
+        Ok(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{string::String, vec, vec::Vec};
+
+    use crate::headers::{message_id::MessageId, Header};
+
+    fn write(id: MessageId) -> Option<String> {
+        let mut output = Vec::new();
+        id.write_header(&mut output, 0)
+            .ok()
+            .map(|_| String::from_utf8(output).unwrap())
+    }
+
+    #[test]
+    fn sanitize_ids() {
+        assert_eq!(
+            write(MessageId::new("<already@bracketed>")).unwrap(),
+            "<already@bracketed>\r\n"
+        );
+        assert_eq!(
+            write(vec![" <a@b> ", "c@d"].into()).unwrap(),
+            "<a@b> <c@d>\r\n"
+        );
+        for id in ["a\r\nInjected: x", "a b@c", "<<a@b>>", "a>b@c", "<>", ""] {
+            assert_eq!(write(MessageId::new(id)), None, "{id:?}");
+        }
+    }
+}
@@ -12,20 +12,121 @@
 pub mod address;
 pub mod content_type;
 pub mod date;
+pub mod folded;
 pub mod message_id;
 pub mod raw;
 pub mod text;
 pub mod url;
 
-use std::io::{self, Write};
+use alloc::{borrow::Cow, format};
+
+use crate::{
+    io::{self, Write},
+    NewlinePolicy, WriteOptions,
+};
 
 use self::{
-    address::Address, content_type::ContentType, date::Date, message_id::MessageId, raw::Raw,
-    text::Text, url::URL,
+    address::Address, content_type::ContentType, date::Date, folded::Folded, message_id::MessageId,
+    raw::Raw, text::Text, url::URL,
 };
 
+/// Maximum length of a line, excluding the CRLF (RFC 5322, Section 2.1.1).
+pub const MAX_LINE_LENGTH: usize = 998;
+
+/// Length at which header values are folded, leaving room within the 78
+/// characters recommended by RFC 5322 for a trailing delimiter.
+pub const MAX_FOLD_LENGTH: usize = 76;
+
+/// Writes a header value.
+///
+/// `bytes_written` is the current column, that is, the length of the
+/// header name and separator (such as `Subject: `) when writing a full
+/// header. After folding, the column is the width of the fold indent.
+/// Writers return the column once done, which is 0 after the final CRLF.
 pub trait Header {
     fn write_header(&self, output: impl Write, bytes_written: usize) -> io::Result<usize>;
+
+    /// Returns `true` if the header has no value to write, such as an
+    /// empty address list, in which case the header is omitted.
+    fn is_empty(&self) -> bool {
+        false
+    }
+
+    /// Write the header using the provided options. Headers that are not
+    /// affected by any option only need to implement `write_header`.
+    fn write_header_with(
+        &self,
+        output: impl Write,
+        bytes_written: usize,
+        _options: &WriteOptions,
+    ) -> io::Result<usize> {
+        self.write_header(output, bytes_written)
+    }
+}
+
+/// Writes a header line, that is, the header name, the separator and the
+/// value, using the separator and fold indentation set in `options`.
+pub(crate) fn write_header_line(
+    mut output: impl Write,
+    name: &str,
+    value: &impl Header,
+    options: &WriteOptions,
+) -> io::Result<usize> {
+    if value.is_empty() {
+        return Ok(0);
+    }
+
+    output.write_all(name.as_bytes())?;
+    output.write_all(options.header_separator.as_bytes())?;
+    let bytes_written = name.len() + options.header_separator.len();
+    if options.fold_indent == "\t" {
+        value.write_header_with(output, bytes_written, options)
+    } else {
+        value.write_header_with(
+            FoldIndentWriter {
+                inner: output,
+                indent: options.fold_indent.as_bytes(),
+                tail: [0; 2],
+            },
+            bytes_written,
+            options,
+        )
+    }
+}
+
+/// Writes a folding line break and returns the column that follows it,
+/// the width of the fold indent. The tab written here is replaced with the
+/// configured indent by `write_header_line`.
+pub(crate) fn write_fold(mut output: impl Write, options: &WriteOptions) -> io::Result<usize> {
+    output.write_all(b"\r\n\t")?;
+    Ok(options.fold_indent.len())
+}
+
+/// Replaces the tab that follows each folding CRLF with a custom indent.
+struct FoldIndentWriter<'a, W: Write> {
+    inner: W,
+    indent: &'a [u8],
+    tail: [u8; 2],
+}
+
+impl<W: Write> Write for FoldIndentWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut start = 0;
+        for (pos, &ch) in buf.iter().enumerate() {
+            if ch == b'\t' && self.tail == *b"\r\n" {
+                self.inner.write_all(&buf[start..pos])?;
+                self.inner.write_all(self.indent)?;
+                start = pos + 1;
+            }
+            self.tail = [self.tail[1], ch];
+        }
+        self.inner.write_all(&buf[start..])?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
@@ -37,6 +138,7 @@ pub enum HeaderType<'x> {
     Text(Text<'x>),
     URL(URL<'x>),
     ContentType(ContentType<'x>),
+    Folded(Folded<'x>),
 }
 
 impl<'x> From<Address<'x>> for HeaderType<'x> {
@@ -72,6 +174,12 @@ impl<'x> From<Text<'x>> for HeaderType<'x> {
     }
 }
 
+impl<'x> From<Folded<'x>> for HeaderType<'x> {
+    fn from(value: Folded<'x>) -> Self {
+        HeaderType::Folded(value)
+    }
+}
+
 impl<'x> From<URL<'x>> for HeaderType<'x> {
     fn from(value: URL<'x>) -> Self {
         HeaderType::URL(value)
@@ -79,24 +187,247 @@ impl<'x> From<URL<'x>> for HeaderType<'x> {
 }
 
 impl<'x> Header for HeaderType<'x> {
+    fn is_empty(&self) -> bool {
+        matches!(self, HeaderType::Address(value) if value.is_empty())
+    }
+
     fn write_header(&self, output: impl Write, bytes_written: usize) -> io::Result<usize> {
+        self.write_header_with(output, bytes_written, &WriteOptions::default())
+    }
+
+    fn write_header_with(
+        &self,
+        output: impl Write,
+        bytes_written: usize,
+        options: &WriteOptions,
+    ) -> io::Result<usize> {
         match self {
-            HeaderType::Address(value) => value.write_header(output, bytes_written),
-            HeaderType::Date(value) => value.write_header(output, bytes_written),
-            HeaderType::MessageId(value) => value.write_header(output, bytes_written),
-            HeaderType::Raw(value) => value.write_header(output, bytes_written),
-            HeaderType::Text(value) => value.write_header(output, bytes_written),
-            HeaderType::URL(value) => value.write_header(output, bytes_written),
-            HeaderType::ContentType(value) => value.write_header(output, bytes_written),
+            HeaderType::Address(value) => value.write_header_with(output, bytes_written, options),
+            HeaderType::Date(value) => value.write_header_with(output, bytes_written, options),
+            HeaderType::MessageId(value) => value.write_header_with(output, bytes_written, options),
+            HeaderType::Raw(value) => value.write_header_with(output, bytes_written, options),
+            HeaderType::Text(value) => value.write_header_with(output, bytes_written, options),
+            HeaderType::URL(value) => value.write_header_with(output, bytes_written, options),
+            HeaderType::Folded(value) => value.write_header_with(output, bytes_written, options),
+            HeaderType::ContentType(value) => {
+                value.write_header_with(output, bytes_written, options)
+            }
         }
     }
 }
 
 impl<'x> HeaderType<'x> {
-    pub fn as_content_type(&self) -> Option<&ContentType> {
+    pub fn as_content_type(&self) -> Option<&ContentType<'_>> {
         match self {
             HeaderType::ContentType(value) => Some(value),
             _ => None,
         }
     }
+
+    /// Rejects or strips, depending on `policy`, the CR and LF characters
+    /// found in the value. Pre-folded values are validated when created.
+    pub(crate) fn check_newlines(&mut self, policy: NewlinePolicy) -> io::Result<()> {
+        match self {
+            HeaderType::Address(value) => check_address_newlines(value, policy),
+            HeaderType::MessageId(value) => value
+                .id
+                .iter_mut()
+                .try_for_each(|id| check_newlines(id, policy)),
+            HeaderType::Raw(value) => check_newlines(&mut value.raw, policy),
+            HeaderType::Text(value) => check_newlines(&mut value.text, policy),
+            HeaderType::URL(value) => value
+                .url
+                .iter_mut()
+                .try_for_each(|url| check_newlines(url, policy)),
+            HeaderType::ContentType(value) => {
+                check_newlines(&mut value.c_type, policy)?;
+                value.attributes.iter_mut().try_for_each(|(key, value)| {
+                    check_newlines(key, policy)?;
+                    check_newlines(value, policy)
+                })
+            }
+            HeaderType::Date(_) | HeaderType::Folded(_) => Ok(()),
+        }
+    }
+}
+
+fn check_address_newlines(address: &mut Address<'_>, policy: NewlinePolicy) -> io::Result<()> {
+    match address {
+        Address::Address(address) => {
+            if let Some(name) = &mut address.name {
+                check_newlines(name, policy)?;
+            }
+            check_newlines(&mut address.email, policy)
+        }
+        Address::Group(group) => {
+            if let Some(name) = &mut group.name {
+                check_newlines(name, policy)?;
+            }
+            group
+                .addresses
+                .iter_mut()
+                .try_for_each(|address| check_address_newlines(address, policy))
+        }
+        Address::List(list) => list
+            .iter_mut()
+            .try_for_each(|address| check_address_newlines(address, policy)),
+    }
+}
+
+fn check_newlines(value: &mut Cow<'_, str>, policy: NewlinePolicy) -> io::Result<()> {
+    if !value.contains(['\r', '\n']) {
+        Ok(())
+    } else if policy == NewlinePolicy::Strip {
+        *value = value.replace(['\r', '\n'], "").into();
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "header value contains line breaks",
+        ))
+    }
+}
+
+/// Returns whether the value is an RFC 2045 token: printable ASCII
+/// characters other than spaces and tspecials.
+pub(crate) fn is_token(value: &str) -> bool {
+    !value.is_empty()
+        && value
+            .bytes()
+            .all(|ch| ch.is_ascii_graphic() && !b"()<>@,;:\\\"/[]?=".contains(&ch))
+}
+
+/// Returns an error if `name` is not a valid header field name, that is,
+/// printable ASCII characters other than the colon.
+pub(crate) fn check_header_name(name: &str) -> io::Result<()> {
+    if name.is_empty() || !name.bytes().all(|ch| ch.is_ascii_graphic() && ch != b':') {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid header name {name:?}"),
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{
+        format,
+        string::{String, ToString},
+        vec,
+        vec::Vec,
+    };
+
+    use super::{
+        address::Address, content_type::ContentType, message_id::MessageId, raw::Raw, text::Text,
+        url::URL, write_header_line, HeaderType, MAX_FOLD_LENGTH,
+    };
+    use crate::WriteOptions;
+
+    type ValueFn = fn(String) -> HeaderType<'static>;
+
+    fn write(name: &str, value: HeaderType<'_>, options: &WriteOptions) -> String {
+        let mut output = Vec::new();
+        write_header_line(&mut output, name, &value, options).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn fold_at_boundary() {
+        let values: [(&str, ValueFn); 6] = [
+            ("To", |value| {
+                Address::new_list(vec![
+                    Address::new_address(Some("Jane"), "jane@example.com"),
+                    Address::new_address(Some(value), "john@example.com"),
+                ])
+                .into()
+            }),
+            ("To", |value| {
+                Address::new_list(vec![
+                    Address::new_address(None::<&str>, "jane@example.com"),
+                    Address::new_address(None::<&str>, format!("{value}@example.com")),
+                ])
+                .into()
+            }),
+            ("Cc", |value| {
+                Address::new_group(
+                    Some("Team"),
+                    vec![
+                        Address::new_address(None::<&str>, "jane@example.com"),
+                        Address::new_address(Some(value), "john@example.com"),
+                    ],
+                )
+                .into()
+            }),
+            ("Content-Type", |value| {
+                ContentType::new("text/plain")
+                    .attribute("charset", "us-ascii")
+                    .attribute("name", value)
+                    .attribute("x", "y")
+                    .into()
+            }),
+            ("References", |value| {
+                MessageId::new_list(["first@example.com".to_string(), value].into_iter()).into()
+            }),
+            ("List-Archive", |value| {
+                URL::new_list(["mailto:list@example.com".to_string(), value].into_iter()).into()
+            }),
+        ];
+
+        for fold_indent in ["\t", "    "] {
+            let options = WriteOptions::new().fold_indent(fold_indent);
+            for (name, value) in values {
+                // A line of exactly 76 characters is not folded, but adding
+                // a single character to it is.
+                let mut exact = None;
+                for len in 1..50 {
+                    let header = write(name, value("a".repeat(len)), &options);
+                    let lines = header.trim_end().split("\r\n").collect::<Vec<_>>();
+                    for line in &lines {
+                        assert!(line.len() <= MAX_FOLD_LENGTH, "{header:?}");
+                    }
+                    if lines.len() == 1 && lines[0].len() == MAX_FOLD_LENGTH {
+                        exact = Some(len);
+                    } else if exact.is_some_and(|exact| exact + 1 == len) {
+                        assert_eq!(lines.len(), 2, "{header:?}");
+                        assert!(lines[1].starts_with(fold_indent), "{header:?}");
+                    }
+                }
+                assert!(exact.is_some(), "{name}");
+            }
+        }
+    }
+
+    #[test]
+    fn fold_unstructured_at_boundary() {
+        // Unstructured values are folded at the first whitespace found once
+        // the line reaches 76 characters.
+        let values: [(&str, ValueFn); 2] = [
+            ("Subject", |value| Text::new(value).into()),
+            ("Comments", |value| Raw::new(value).into()),
+        ];
+        for (name, value) in values {
+            let prefix = name.len() + 2;
+            for (len, expected) in [
+                (MAX_FOLD_LENGTH - prefix - 1, None),
+                (MAX_FOLD_LENGTH - prefix, Some(MAX_FOLD_LENGTH)),
+            ] {
+                let header = write(
+                    name,
+                    value(format!("{} b", "a".repeat(len))),
+                    &WriteOptions::default(),
+                );
+                let lines = header.trim_end().split("\r\n").collect::<Vec<_>>();
+                match expected {
+                    None => assert_eq!(lines.len(), 1, "{header:?}"),
+                    Some(expected) => {
+                        assert_eq!(lines.len(), 2, "{header:?}");
+                        assert_eq!(lines[0].len(), expected, "{header:?}");
+                        assert_eq!(lines[1], "\t b", "{header:?}");
+                    }
+                }
+            }
+        }
+    }
 }
@@ -9,12 +9,15 @@
  * except according to those terms.
  */
 
-use std::borrow::Cow;
+use alloc::borrow::Cow;
 
-use super::Header;
+use crate::WriteOptions;
+
+use super::{folded::is_valid_folded, write_fold, Header, MAX_FOLD_LENGTH};
 
 /// Raw e-mail header.
-/// Raw headers are not encoded, only line-wrapped.
+/// Raw headers are not encoded, only line-wrapped. Values with line breaks
+/// other than folding whitespace are rejected when written.
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Raw<'x> {
     pub raw: Cow<'x, str>,
@@ -39,13 +42,31 @@ where
 impl<'x> Header for Raw<'x> {
     fn write_header(
         &self,
-        mut output: impl std::io::Write,
+        output: impl crate::io::Write,
+        bytes_written: usize,
+    ) -> crate::io::Result<usize> {
+        self.write_header_with(output, bytes_written, &WriteOptions::default())
+    }
+
+    fn write_header_with(
+        &self,
+        mut output: impl crate::io::Write,
         mut bytes_written: usize,
-    ) -> std::io::Result<usize> {
+        options: &WriteOptions,
+    ) -> crate::io::Result<usize> {
+        if self.raw.contains(['\r', '\n']) && !is_valid_folded(&self.raw) {
+            return Err(crate::io::Error::new(
+                crate::io::ErrorKind::InvalidInput,
+                "header value contains line breaks that are not folding whitespace",
+            ));
+        }
+
         for (pos, &ch) in self.raw.as_bytes().iter().enumerate() {
-            if bytes_written >= 76 && ch.is_ascii_whitespace() && pos < self.raw.len() - 1 {
-                output.write_all(b"\r\n\t")?;
-                bytes_written = 1;
+            if bytes_written >= MAX_FOLD_LENGTH
+                && ch.is_ascii_whitespace()
+                && pos < self.raw.len() - 1
+            {
+                bytes_written = write_fold(&mut output, options)?;
             }
             output.write_all(&[ch])?;
             bytes_written += 1;
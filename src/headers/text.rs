@@ -9,15 +9,18 @@
  * except according to those terms.
  */
 
-use std::borrow::Cow;
+use alloc::borrow::Cow;
 
-use crate::encoders::{
-    base64::base64_encode_mime,
-    encode::{get_encoding_type, EncodingType},
-    quoted_printable::quoted_printable_encode,
+use crate::{
+    encoders::{
+        base64::{base64_encode_mime, base64_encoded_len},
+        encode::{get_encoding_type, is_raw_utf8_header, EncodingType},
+        quoted_printable::{inline_quoted_printable_len, quoted_printable_encode, QpMode},
+    },
+    WriteOptions,
 };
 
-use super::Header;
+use super::{write_fold, Header, MAX_FOLD_LENGTH, MAX_LINE_LENGTH};
 
 /// Unstructured text e-mail header.
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
@@ -41,43 +44,119 @@ where
     }
 }
 
+/// Maximum length of an RFC 2047 encoded-word, including delimiters.
+pub const MAX_ENCODED_WORD_LEN: usize = 75;
+
+fn write_encoded_word(
+    mut output: impl crate::io::Write,
+    prefix: &[u8],
+    chunk: &[u8],
+    is_base64: bool,
+    is_first: bool,
+) -> crate::io::Result<()> {
+    if !is_first {
+        output.write_all(b"\t")?;
+    }
+    output.write_all(prefix)?;
+    if is_base64 {
+        base64_encode_mime(chunk, &mut output, true)?;
+    } else {
+        quoted_printable_encode(chunk, &mut output, QpMode::Inline)?;
+    }
+    output.write_all(b"?=\r\n")
+}
+
 impl<'x> Header for Text<'x> {
     fn write_header(
         &self,
-        mut output: impl std::io::Write,
+        output: impl crate::io::Write,
+        bytes_written: usize,
+    ) -> crate::io::Result<usize> {
+        self.write_header_with(output, bytes_written, &WriteOptions::default())
+    }
+
+    fn write_header_with(
+        &self,
+        mut output: impl crate::io::Write,
         mut bytes_written: usize,
-    ) -> std::io::Result<usize> {
-        match get_encoding_type(self.text.as_bytes(), true, false) {
-            EncodingType::Base64 => {
-                for (pos, chunk) in self.text.as_bytes().chunks(76 - bytes_written).enumerate() {
-                    if pos > 0 {
-                        output.write_all(b"\t")?;
-                    }
-                    output.write_all(b"=?utf-8?B?")?;
-                    base64_encode_mime(chunk, &mut output, true)?;
-                    output.write_all(b"?=\r\n")?;
+        options: &WriteOptions,
+    ) -> crate::io::Result<usize> {
+        let encoding_type = if options.utf8_headers && is_raw_utf8_header(&self.text) {
+            EncodingType::None
+        } else {
+            get_encoding_type(self.text.as_bytes(), true, false)
+        };
+
+        match encoding_type {
+            encoding_type @ (EncodingType::Base64 | EncodingType::QuotedPrintable(_)) => {
+                let (prefix, is_base64): (&[u8], bool) = match encoding_type {
+                    EncodingType::Base64 => (b"=?utf-8?B?", true),
+                    EncodingType::QuotedPrintable(false) => (b"=?utf-8?Q?", false),
+                    _ => (b"=?us-ascii?Q?", false),
+                };
+
+                // RFC 2047 limits encoded-words to 75 characters, and lines
+                // containing them to 76 characters.
+                let overhead = prefix.len() + 2;
+                let folded_max_len = MAX_ENCODED_WORD_LEN
+                    .min(MAX_FOLD_LENGTH.saturating_sub(options.fold_indent.len()));
+                let mut max_len =
+                    MAX_ENCODED_WORD_LEN.min(MAX_FOLD_LENGTH.saturating_sub(bytes_written));
+                let mut is_first = true;
+                if max_len < overhead + 12 {
+                    // Too little room left, start on a folded line
+                    output.write_all(b"\r\n")?;
+                    is_first = false;
+                    max_len = folded_max_len;
                 }
-            }
-            EncodingType::QuotedPrintable(is_ascii) => {
-                for (pos, chunk) in self.text.as_bytes().chunks(76 - bytes_written).enumerate() {
-                    if pos > 0 {
-                        output.write_all(b"\t")?;
-                    }
-                    if !is_ascii {
-                        output.write_all(b"=?utf-8?Q?")?;
+
+                let text = self.text.as_bytes();
+                let mut word_start = 0;
+                let mut word_len = 0;
+
+                for (pos, ch) in self.text.char_indices() {
+                    let ch_bytes = &text[pos..pos + ch.len_utf8()];
+                    let new_len = if is_base64 {
+                        base64_encoded_len(pos - word_start + ch_bytes.len(), true)
+                    } else {
+                        word_len + inline_quoted_printable_len(ch_bytes)
+                    };
+
+                    if overhead + new_len > max_len && pos > word_start {
+                        write_encoded_word(
+                            &mut output,
+                            prefix,
+                            &text[word_start..pos],
+                            is_base64,
+                            is_first,
+                        )?;
+                        is_first = false;
+                        max_len = folded_max_len;
+                        word_start = pos;
+                        word_len = inline_quoted_printable_len(ch_bytes);
                     } else {
-                        output.write_all(b"=?us-ascii?Q?")?;
+                        word_len = new_len;
                     }
-                    quoted_printable_encode(chunk, &mut output, true, false)?;
-                    output.write_all(b"?=\r\n")?;
                 }
+
+                write_encoded_word(
+                    &mut output,
+                    prefix,
+                    &text[word_start..],
+                    is_base64,
+                    is_first,
+                )?;
             }
             EncodingType::None => {
+                // Fold at whitespace past 76 characters, or mid-word when a
+                // line would otherwise exceed the 998 octet limit.
                 for (pos, &ch) in self.text.as_bytes().iter().enumerate() {
-                    if bytes_written >= 76 && ch.is_ascii_whitespace() && pos < self.text.len() - 1
+                    if (bytes_written >= MAX_FOLD_LENGTH
+                        && ch.is_ascii_whitespace()
+                        && pos < self.text.len() - 1)
+                        || bytes_written >= MAX_LINE_LENGTH
                     {
-                        output.write_all(b"\r\n\t")?;
-                        bytes_written = 1;
+                        bytes_written = write_fold(&mut output, options)?;
                     }
                     output.write_all(&[ch])?;
                     bytes_written += 1;
@@ -88,3 +167,71 @@ impl<'x> Header for Text<'x> {
         Ok(0)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use alloc::{format, string::String, vec::Vec};
+
+    use mail_parser::MessageParser;
+
+    use crate::headers::{text::Text, Header};
+
+    #[test]
+    fn encoded_word_length() {
+        for subject in [
+            "ハロー・ワールド ".repeat(20),
+            "¡El ñandú comió ñoquis! ".repeat(10),
+            "Saint-Exupéry ".repeat(30),
+            "a=b?c ".repeat(40) + "ñ",
+        ] {
+            let mut output = b"Subject: ".to_vec();
+            Text::new(subject.as_str())
+                .write_header(&mut output, "Subject: ".len())
+                .unwrap();
+            let header = String::from_utf8(output.clone()).unwrap();
+
+            for line in header.split("\r\n") {
+                assert!(line.len() <= 76, "{line:?}");
+                for word in line.split(|ch: char| ch.is_ascii_whitespace()) {
+                    if word.starts_with("=?") {
+                        assert!(word.len() <= 75, "{word:?}");
+                    }
+                }
+            }
+
+            output.extend_from_slice(b"\r\nbody");
+            assert_eq!(
+                MessageParser::new().parse(&output).unwrap().subject(),
+                Some(subject.as_str())
+            );
+        }
+    }
+
+    #[test]
+    fn fold_long_word() {
+        let subject = "a".repeat(1100);
+        let mut output = b"Subject: ".to_vec();
+        Text::new(subject.as_str())
+            .write_header(&mut output, "Subject: ".len())
+            .unwrap();
+        let header = String::from_utf8(output).unwrap();
+        let lines = header.split("\r\n").collect::<Vec<_>>();
+
+        assert_eq!(lines.len(), 3, "{header:?}");
+        assert_eq!(lines[0].len(), 998);
+        assert!(lines[1].starts_with('\t'));
+        assert_eq!(lines[2], "");
+        assert_eq!(lines.concat().replace('\t', "").len(), 9 + 1100);
+
+        // Whitespace is still preferred when available
+        let subject = format!("{} {}", "a".repeat(80), "b".repeat(20));
+        let mut output = Vec::new();
+        Text::new(subject.as_str())
+            .write_header(&mut output, "Subject: ".len())
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            format!("{}\r\n\t {}\r\n", "a".repeat(80), "b".repeat(20))
+        );
+    }
+}
@@ -9,9 +9,11 @@
  * except according to those terms.
  */
 
-use std::borrow::Cow;
+use alloc::{borrow::Cow, string::String, vec, vec::Vec};
 
-use super::Header;
+use crate::{io, WriteOptions};
+
+use super::{write_fold, Header, MAX_FOLD_LENGTH, MAX_LINE_LENGTH};
 
 /// URL header, used mostly on List-* headers
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
@@ -78,31 +80,46 @@ where
     }
 }
 
+/// URLs cannot contain whitespace, so headers are only folded before the
+/// `<` of each URL. A URL that does not fit in a line of 998 octets, once
+/// folded, is rejected with an `InvalidInput` error.
 impl<'x> Header for URL<'x> {
-    fn write_header(
+    fn write_header(&self, output: impl io::Write, bytes_written: usize) -> io::Result<usize> {
+        self.write_header_with(output, bytes_written, &WriteOptions::default())
+    }
+
+    fn write_header_with(
         &self,
-        mut output: impl std::io::Write,
+        mut output: impl io::Write,
         mut bytes_written: usize,
-    ) -> std::io::Result<usize> {
+        options: &WriteOptions,
+    ) -> io::Result<usize> {
         for (pos, url) in self.url.iter().enumerate() {
-            if pos > 0 {
-                if bytes_written + url.len() + 2 >= 76 {
-                    output.write_all(b"\r\n\t")?;
-                    bytes_written = 1;
-                } else {
-                    output.write_all(b" ")?;
-                    bytes_written += 1;
-                }
+            // Delimiters plus the separating comma
+            let len = url.len() + if pos < self.url.len() - 1 { 3 } else { 2 };
+            if 1 + len > MAX_LINE_LENGTH {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "URL exceeds the maximum line length",
+                ));
+            }
+
+            if (pos > 0 && bytes_written + 1 + len > MAX_FOLD_LENGTH)
+                || bytes_written + len > MAX_LINE_LENGTH
+            {
+                bytes_written = write_fold(&mut output, options)?;
+            } else if pos > 0 {
+                output.write_all(b" ")?;
+                bytes_written += 1;
             }
             output.write_all(b"<")?;
             output.write_all(url.as_bytes())?;
             if pos < self.url.len() - 1 {
                 output.write_all(b">,")?;
-                bytes_written += url.len() + 3;
             } else {
                 output.write_all(b">")?;
-                bytes_written += url.len() + 2;
             }
+            bytes_written += len;
         }
 
         if bytes_written > 0 {
@@ -112,3 +129,60 @@ impl<'x> Header for URL<'x> {
         Ok(0)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use alloc::{format, string::String, vec::Vec};
+
+    use crate::headers::{url::URL, Header};
+
+    fn write_url(url: URL<'_>) -> crate::io::Result<String> {
+        let mut output = Vec::new();
+        url.write_header(&mut output, "List-Archive: ".len())?;
+        Ok(String::from_utf8(output).unwrap())
+    }
+
+    #[test]
+    fn fold_urls() {
+        let long_url = format!("https://example.com/track?id={}", "a".repeat(170));
+        let output = write_url(URL::new_list(
+            [
+                "mailto:list@example.com",
+                long_url.as_str(),
+                "https://example.com/archive",
+                "https://example.com/archive/2024",
+            ]
+            .into_iter(),
+        ))
+        .unwrap();
+        assert_eq!(
+            output,
+            format!(
+                concat!(
+                    "<mailto:list@example.com>,\r\n",
+                    "\t<{}>,\r\n",
+                    "\t<https://example.com/archive>, <https://example.com/archive/2024>\r\n"
+                ),
+                long_url
+            )
+        );
+
+        let output = write_url(URL::new_list(
+            ["https://a.example.com", "https://b.example.com"].into_iter(),
+        ))
+        .unwrap();
+        assert_eq!(
+            output,
+            "<https://a.example.com>, <https://b.example.com>\r\n"
+        );
+
+        // Over-long URLs are written on their own line, if they fit
+        let long_url = format!("https://example.com/{}", "a".repeat(970));
+        let output = write_url(URL::new(long_url.as_str())).unwrap();
+        assert_eq!(output, format!("\r\n\t<{long_url}>\r\n"));
+        assert!(output.split("\r\n").all(|line| line.len() <= 998));
+
+        let long_url = format!("https://example.com/{}", "a".repeat(1000));
+        assert!(write_url(URL::new(long_url)).is_err());
+    }
+}
@@ -0,0 +1,127 @@
+/*
+ * Copyright Stalwart Labs Ltd. See the COPYING
+ * file at the top-level directory of this distribution.
+ *
+ * Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+ * https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+ * <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+ * option. This file may not be copied, modified, or distributed
+ * except according to those terms.
+ */
+
+//! I/O primitives used by the builder.
+//!
+//! With the `std` feature enabled (the default) these are re-exports of
+//! `std::io`. Without it, a minimal `Write` trait and error type are
+//! provided so that messages can be built on `no_std` + `alloc` targets.
+
+#[cfg(feature = "std")]
+pub use std::io::{Error, ErrorKind, Result, Write};
+
+#[cfg(not(feature = "std"))]
+pub use self::no_std::{Error, ErrorKind, Result, Write};
+
+#[cfg(not(feature = "std"))]
+mod no_std {
+    use alloc::{
+        string::{String, ToString},
+        vec::Vec,
+    };
+    use core::fmt::{self, Display};
+
+    pub type Result<T> = core::result::Result<T, Error>;
+
+    /// Categories of errors returned by the builder.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum ErrorKind {
+        InvalidInput,
+        InvalidData,
+        WriteZero,
+        Other,
+    }
+
+    /// Error returned by `Write` implementations and the builder.
+    #[derive(Debug)]
+    pub struct Error {
+        kind: ErrorKind,
+        error: String,
+    }
+
+    impl Error {
+        /// Create a new error from a kind and a description.
+        pub fn new(kind: ErrorKind, error: impl Display) -> Self {
+            Error {
+                kind,
+                error: error.to_string(),
+            }
+        }
+
+        /// Create a new error of kind `ErrorKind::Other`.
+        pub fn other(error: impl Display) -> Self {
+            Self::new(ErrorKind::Other, error)
+        }
+
+        /// Returns the kind of this error.
+        pub fn kind(&self) -> ErrorKind {
+            self.kind
+        }
+    }
+
+    impl Display for Error {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str(&self.error)
+        }
+    }
+
+    impl core::error::Error for Error {}
+
+    /// A byte-oriented sink, mirroring `std::io::Write`.
+    pub trait Write {
+        fn write(&mut self, buf: &[u8]) -> Result<usize>;
+
+        fn write_all(&mut self, mut buf: &[u8]) -> Result<()> {
+            while !buf.is_empty() {
+                match self.write(buf)? {
+                    0 => {
+                        return Err(Error::new(
+                            ErrorKind::WriteZero,
+                            "failed to write whole buffer",
+                        ))
+                    }
+                    n => buf = &buf[n..],
+                }
+            }
+            Ok(())
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    impl<W: Write + ?Sized> Write for &mut W {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            (**self).write(buf)
+        }
+
+        fn write_all(&mut self, buf: &[u8]) -> Result<()> {
+            (**self).write_all(buf)
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            (**self).flush()
+        }
+    }
+
+    impl Write for Vec<u8> {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            self.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn write_all(&mut self, buf: &[u8]) -> Result<()> {
+            self.extend_from_slice(buf);
+            Ok(())
+        }
+    }
+}
@@ -21,6 +21,8 @@
 //! - Generates **e-mail** messages conforming to the Internet Message Format standard (_RFC 5322_).
 //! - Full **MIME** support (_RFC 2045 - 2049_) with automatic selection of the most optimal encoding for each message body part.
 //! - **Fast Base64 encoding** based on Chromium's decoder ([the fastest non-SIMD encoder](https://github.com/lemire/fastbase64)).
+//! - `no_std` support (requires `alloc`) by disabling the default `std` feature,
+//!   seeded through `mime::set_entropy_seed`.
 //! - Minimal dependencies.
 //!
 //! Please note that this library does not support sending or parsing e-mail messages as these functionalities are provided by the crates [`mail-send`](https://crates.io/crates/mail-send) and [`mail-parser`](https://crates.io/crates/mail-parser).
@@ -30,6 +32,7 @@
 //! Build a simple e-mail message with a text body and one attachment:
 //!
 //! ```rust
+//! # #[cfg(feature = "std")] {
 //!     use mail_builder::MessageBuilder;
 //!
 //!    // Build a simple text message with a single attachment
@@ -44,12 +47,14 @@
 //!        
 //!    // Print raw message
 //!    println!("{}", eml);
+//! # }
 //! ```
 //!
 //! More complex messages with grouped addresses, inline parts and
 //! multipart/alternative sections can also be easily built:
 //!
 //! ```rust
+//! # #[cfg(feature = "std")] {
 //!     use mail_builder::{headers::url::URL, MessageBuilder};
 //!     use std::fs::File;
 //!
@@ -109,11 +114,13 @@
 //!        // Write the message to a file
 //!        .write_to(File::create("message.eml").unwrap())
 //!        .unwrap();
+//! # }
 //! ```
 //!
 //! Nested MIME body structures can be created using the `body` method:
 //!
 //! ```rust
+//! # #[cfg(feature = "std")] {
 //!     use mail_builder::{headers::address::Address, mime::MimePart, MessageBuilder};
 //!     use std::fs::File;
 //!
@@ -177,6 +184,7 @@
 //!        // Write the message to a file
 //!        .write_to(File::create("nested-message.eml").unwrap())
 //!        .unwrap();
+//! # }
 //! ```
 //!
 //! ## Testing
@@ -197,8 +205,8 @@
 //!
 //! Licensed under either of
 //!
-//!  * Apache License, Version 2.0 ([LICENSE-APACHE](LICENSE-APACHE) or http://www.apache.org/licenses/LICENSE-2.0)
-//!  * MIT license ([LICENSE-MIT](LICENSE-MIT) or http://opensource.org/licenses/MIT)
+//!  * Apache License, Version 2.0 ([LICENSE-APACHE](LICENSE-APACHE) or <http://www.apache.org/licenses/LICENSE-2.0>)
+//!  * MIT license ([LICENSE-MIT](LICENSE-MIT) or <http://opensource.org/licenses/MIT>)
 //!
 //! at your option.
 //!
@@ -210,24 +218,460 @@
 //!
 //! [COPYING]: https://github.com/stalwartlabs/mail-builder/blob/main/COPYING
 //!
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
 pub mod encoders;
 pub mod headers;
+pub mod io;
 pub mod mime;
+mod sha256;
+pub mod stream;
 
-use std::{
+use alloc::{
     borrow::Cow,
-    io::{self, Write},
+    format,
+    string::{FromUtf8Error, String},
+    vec,
+    vec::Vec,
 };
 
+use encoders::{counting::CountingWriter, line_ending::LineEndingWriter};
 use headers::{
     address::Address,
+    check_header_name,
     content_type::ContentType,
     date::Date,
-    message_id::{generate_message_id_header, MessageId},
+    folded::Folded,
+    message_id::{default_hostname, generate_message_id, MessageId},
+    raw::Raw,
     text::Text,
-    Header, HeaderType,
+    write_header_line, Header, HeaderType,
 };
-use mime::{BodyPart, MimePart};
+use io::Write;
+use mime::{
+    content_hash, estimated_header_size, BodyPart, ContentHasher, MimePart, ESTIMATED_BOUNDARY_LEN,
+    ESTIMATED_CONTAINER_LEN,
+};
+
+/// Returns the offset of the body in a serialized message, that is,
+/// the position right after the first empty line.
+pub(crate) fn split_body(message: &[u8]) -> Option<usize> {
+    message
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .map(|pos| pos + 4)
+}
+
+/// Canonicalize a message body using the DKIM "relaxed" body
+/// canonicalization algorithm (RFC 6376, Section 3.4.4): whitespace at the
+/// end of lines is removed, other whitespace runs are reduced to a single
+/// space and trailing empty lines are reduced to a single CRLF.
+pub fn canonical_body_relaxed(body: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(body.len());
+    let mut line = Vec::new();
+    let mut pending_crlf = 0;
+
+    for raw_line in body.split(|&ch| ch == b'\n') {
+        let raw_line = raw_line.strip_suffix(b"\r").unwrap_or(raw_line);
+        line.clear();
+        for &ch in raw_line {
+            if ch == b' ' || ch == b'\t' {
+                if line.last() != Some(&b' ') {
+                    line.push(b' ');
+                }
+            } else {
+                line.push(ch);
+            }
+        }
+        if line.last() == Some(&b' ') {
+            line.pop();
+        }
+
+        if !line.is_empty() {
+            for _ in 0..pending_crlf {
+                output.extend_from_slice(b"\r\n");
+            }
+            output.extend_from_slice(&line);
+            pending_crlf = 1;
+        } else {
+            pending_crlf += 1;
+        }
+    }
+
+    if !output.is_empty() {
+        output.extend_from_slice(b"\r\n");
+    }
+
+    output
+}
+
+/// Removes parts with the same content type and contents as a previous part.
+fn dedupe_parts(parts: &mut Vec<MimePart<'_>>) {
+    let mut hashes: Vec<(u64, usize)> = Vec::with_capacity(parts.len());
+    let mut deduped = Vec::with_capacity(parts.len());
+
+    for part in parts.drain(..) {
+        if let Some(contents) = part.leaf_contents() {
+            let hash = content_hash(contents);
+            let is_duplicate = hashes.iter().any(|&(prev_hash, pos)| {
+                let prev: &MimePart<'_> = &deduped[pos];
+                prev_hash == hash
+                    && prev.leaf_contents() == Some(contents)
+                    && prev.content_type() == part.content_type()
+            });
+            if is_duplicate {
+                continue;
+            }
+            hashes.push((hash, deduped.len()));
+        }
+        deduped.push(part);
+    }
+
+    *parts = deduped;
+}
+
+/// Returns the byte ranges of the quoted `src` attribute values of the
+/// `<img>` tags in an HTML document.
+fn find_image_sources(html: &str) -> Vec<(usize, usize)> {
+    let bytes = html.as_bytes();
+    let mut sources = Vec::new();
+    let mut pos = 0;
+
+    while let Some(start) = bytes[pos..]
+        .windows(5)
+        .position(|window| {
+            window[..4].eq_ignore_ascii_case(b"<img") && window[4].is_ascii_whitespace()
+        })
+        .map(|start| pos + start)
+    {
+        let end = bytes[start..]
+            .iter()
+            .position(|&ch| ch == b'>')
+            .map_or(bytes.len(), |end| start + end);
+        let skip_whitespace = |mut pos: usize| {
+            while pos < end && bytes[pos].is_ascii_whitespace() {
+                pos += 1;
+            }
+            pos
+        };
+
+        for attr_start in start + 5..end.saturating_sub(3) {
+            if bytes[attr_start - 1].is_ascii_whitespace()
+                && bytes[attr_start..attr_start + 3].eq_ignore_ascii_case(b"src")
+            {
+                let pos = skip_whitespace(attr_start + 3);
+                if pos < end && bytes[pos] == b'=' {
+                    let pos = skip_whitespace(pos + 1);
+                    if pos < end && (bytes[pos] == b'"' || bytes[pos] == b'\'') {
+                        if let Some(len) =
+                            bytes[pos + 1..end].iter().position(|&ch| ch == bytes[pos])
+                        {
+                            sources.push((pos + 1, pos + 1 + len));
+                        }
+                    }
+                    break;
+                }
+            }
+        }
+        pos = end;
+    }
+
+    sources
+}
+
+fn strip_angle_brackets(id: Cow<'_, str>) -> Cow<'_, str> {
+    let trimmed = id.trim().trim_start_matches('<').trim_end_matches('>');
+    if trimmed.len() == id.len() {
+        id
+    } else {
+        String::from(trimmed).into()
+    }
+}
+
+/// Basic syntactic check of an RFC 5321 addr-spec: a dot-atom or quoted
+/// local part, followed by a domain or address literal.
+pub(crate) fn is_valid_addr_spec(addr: &str) -> bool {
+    let is_atext =
+        |ch: u8| ch.is_ascii_alphanumeric() || b"!#$%&'*+-/=?^_`{|}~".contains(&ch) || ch >= 0x80;
+    let is_dot_atom = |value: &str| {
+        value
+            .split('.')
+            .all(|atom| !atom.is_empty() && atom.bytes().all(is_atext))
+    };
+
+    addr.rsplit_once('@').is_some_and(|(local, domain)| {
+        (is_dot_atom(local)
+            || (local.len() > 2
+                && local.starts_with('"')
+                && local.ends_with('"')
+                && local[1..local.len() - 1]
+                    .bytes()
+                    .all(|ch| ch == b' ' || (ch.is_ascii_graphic() && ch != b'"') || ch >= 0x80)))
+            && (is_dot_atom(domain)
+                || (domain.starts_with('[')
+                    && domain.ends_with(']')
+                    && domain[1..domain.len() - 1]
+                        .bytes()
+                        .all(|ch| ch.is_ascii_graphic() && !b"[]\\".contains(&ch))))
+    })
+}
+
+/// Basic syntactic check of a BCP 47 language tag: a primary subtag of
+/// letters followed by subtags of up to 8 letters or digits.
+fn is_valid_language_tag(tag: &str) -> bool {
+    let mut subtags = tag.split('-');
+    subtags.next().is_some_and(|primary| {
+        (1..=8).contains(&primary.len()) && primary.bytes().all(|ch| ch.is_ascii_alphabetic())
+    }) && subtags.all(|subtag| {
+        (1..=8).contains(&subtag.len()) && subtag.bytes().all(|ch| ch.is_ascii_alphanumeric())
+    })
+}
+
+/// Product token appended to the User-Agent and X-Mailer headers by
+/// `MessageBuilder::mailer`.
+pub const MAILER_PRODUCT: &str = concat!("mail-builder/", env!("CARGO_PKG_VERSION"));
+
+/// Number of bytes written by `MessageBuilder::write_to_with_stats`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct WriteStats {
+    pub total_bytes: usize,
+    pub header_bytes: usize,
+    pub body_bytes: usize,
+}
+
+/// Error returned by `MessageBuilder::into_vec` and
+/// `MessageBuilder::into_string`.
+#[derive(Debug)]
+pub enum BuildError {
+    /// The message could not be built, such as when a header value
+    /// contains line breaks.
+    Io(io::Error),
+    /// The message contains bytes that are not valid UTF-8.
+    Utf8(FromUtf8Error),
+}
+
+impl core::fmt::Display for BuildError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            BuildError::Io(err) => write!(f, "failed to build message: {err}"),
+            BuildError::Utf8(err) => write!(f, "message is not valid UTF-8: {err}"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for BuildError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BuildError::Io(err) => Some(err),
+            BuildError::Utf8(err) => Some(err),
+        }
+    }
+}
+
+impl From<io::Error> for BuildError {
+    fn from(err: io::Error) -> Self {
+        BuildError::Io(err)
+    }
+}
+
+impl From<FromUtf8Error> for BuildError {
+    fn from(err: FromUtf8Error) -> Self {
+        BuildError::Utf8(err)
+    }
+}
+
+/// Order of the text and HTML bodies in the multipart/alternative container.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AltOrder {
+    /// Text body first, as recommended by RFC 2046.
+    #[default]
+    TextFirst,
+    /// HTML body first.
+    HtmlFirst,
+}
+
+/// How the fallible header setters, such as `MessageBuilder::try_header`,
+/// handle CR and LF characters in header values.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NewlinePolicy {
+    /// Return an `InvalidInput` error.
+    #[default]
+    Reject,
+    /// Remove the CR and LF characters.
+    Strip,
+}
+
+/// Line ending used when writing a message.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LineEnding {
+    /// CRLF, as required on the wire (RFC 5322).
+    #[default]
+    CrLf,
+    /// LF only, as used when storing messages in Unix mailboxes.
+    Lf,
+}
+
+/// Value of the Auto-Submitted header (RFC 3834).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AutoSubmitted {
+    /// Generated by an automatic process, such as a notification.
+    AutoGenerated,
+    /// Automatic response to another message, such as a bounce.
+    AutoReplied,
+    /// Written by a person.
+    No,
+}
+
+impl AutoSubmitted {
+    /// Returns the header value.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AutoSubmitted::AutoGenerated => "auto-generated",
+            AutoSubmitted::AutoReplied => "auto-replied",
+            AutoSubmitted::No => "no",
+        }
+    }
+}
+
+/// Value of the non-standard Precedence header, honored by most
+/// auto-responders to avoid replying to automated mail.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Precedence {
+    /// Bulk mail, such as newsletters and notifications.
+    Bulk,
+    /// Mail distributed through a mailing list.
+    List,
+    /// Unsolicited or low priority mail.
+    Junk,
+}
+
+impl Precedence {
+    /// Returns the header value.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Precedence::Bulk => "bulk",
+            Precedence::List => "list",
+            Precedence::Junk => "junk",
+        }
+    }
+}
+
+/// Options controlling how a message is serialized.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WriteOptions {
+    pub utf8_headers: bool,
+    pub trailing_crlf: Option<bool>,
+    pub max_depth: usize,
+    pub header_separator: &'static str,
+    pub fold_indent: &'static str,
+    pub strict: bool,
+    pub ascii_charset_label: bool,
+    pub line_ending: LineEnding,
+}
+
+impl Default for WriteOptions {
+    fn default() -> Self {
+        Self {
+            utf8_headers: false,
+            trailing_crlf: None,
+            max_depth: 100,
+            header_separator: ": ",
+            fold_indent: "\t",
+            strict: false,
+            ascii_charset_label: false,
+            line_ending: LineEnding::CrLf,
+        }
+    }
+}
+
+impl WriteOptions {
+    /// Create the default options.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Write non-ASCII header values as raw UTF-8 (RFC 6532) rather than
+    /// RFC 2047 encoded-words. Only enable when the transport supports
+    /// SMTPUTF8.
+    pub fn utf8_headers(mut self, value: bool) -> Self {
+        self.utf8_headers = value;
+        self
+    }
+
+    /// Control the end of the message. When `true`, the message always ends
+    /// with a CRLF. When `false`, no CRLF is written after the closing
+    /// boundary, so the message ends at its last content byte. By default,
+    /// multipart messages end with a CRLF and single part messages end with
+    /// their contents.
+    pub fn trailing_crlf(mut self, value: bool) -> Self {
+        self.trailing_crlf = Some(value);
+        self
+    }
+
+    /// Set the maximum nesting depth of multipart bodies. Writing a message
+    /// nested deeper fails with an `InvalidInput` error. Defaults to 100.
+    pub fn max_depth(mut self, value: usize) -> Self {
+        self.max_depth = value;
+        self
+    }
+
+    /// Set the separator written between header names and values, which
+    /// must be a colon optionally followed by spaces or tabs. Defaults
+    /// to `": "`.
+    pub fn header_separator(mut self, value: &'static str) -> Self {
+        self.header_separator = value;
+        self
+    }
+
+    /// Set the whitespace used to indent folded header lines. Defaults
+    /// to a single tab.
+    pub fn fold_indent(mut self, value: &'static str) -> Self {
+        self.fold_indent = value;
+        self
+    }
+
+    /// Fail with an `InvalidData` error when a part declared as 7bit through
+    /// an explicit Content-Transfer-Encoding header contains non-ASCII bytes.
+    /// Parts forced to `EncodingType::None` are always checked.
+    pub fn strict(mut self, value: bool) -> Self {
+        self.strict = value;
+        self
+    }
+
+    /// Label text parts whose contents are all ASCII as `charset=us-ascii`
+    /// instead of `utf-8`.
+    pub fn ascii_charset_label(mut self, value: bool) -> Self {
+        self.ascii_charset_label = value;
+        self
+    }
+
+    /// Set the line ending of the message, including the soft line breaks
+    /// of encoded contents. Defaults to CRLF.
+    pub fn line_ending(mut self, value: LineEnding) -> Self {
+        self.line_ending = value;
+        self
+    }
+
+    pub(crate) fn validate(&self) -> io::Result<()> {
+        let is_wsp = |value: &str| value.bytes().all(|ch| ch == b' ' || ch == b'\t');
+        if !self.header_separator.strip_prefix(':').is_some_and(is_wsp) {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "header separator must be a colon followed by optional whitespace",
+            ))
+        } else if self.fold_indent.is_empty() || !is_wsp(self.fold_indent) {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "fold indent must be whitespace",
+            ))
+        } else {
+            Ok(())
+        }
+    }
+}
 
 /// Builds an RFC5322 compliant MIME email message.
 #[derive(Clone, Debug)]
@@ -236,7 +680,17 @@ pub struct MessageBuilder<'x> {
     pub html_body: Option<MimePart<'x>>,
     pub text_body: Option<MimePart<'x>>,
     pub attachments: Option<Vec<MimePart<'x>>>,
+    pub inline: Option<Vec<MimePart<'x>>>,
     pub body: Option<MimePart<'x>>,
+    pub force_multipart: bool,
+    pub alternative_content_type: Option<ContentType<'x>>,
+    pub top_level_type: Option<Cow<'x, str>>,
+    pub alternative_order: AltOrder,
+    pub write_options: WriteOptions,
+    pub dedupe_attachments: bool,
+    pub message_id_domain: Option<Cow<'x, str>>,
+    pub envelope_from: Option<Cow<'x, str>>,
+    pub newline_policy: NewlinePolicy,
 }
 
 impl<'x> Default for MessageBuilder<'x> {
@@ -253,7 +707,17 @@ impl<'x> MessageBuilder<'x> {
             html_body: None,
             text_body: None,
             attachments: None,
+            inline: None,
             body: None,
+            force_multipart: false,
+            alternative_content_type: None,
+            top_level_type: None,
+            alternative_order: AltOrder::TextFirst,
+            write_options: WriteOptions::default(),
+            dedupe_attachments: false,
+            message_id_domain: None,
+            envelope_from: None,
+            newline_policy: NewlinePolicy::Reject,
         }
     }
 
@@ -263,6 +727,19 @@ impl<'x> MessageBuilder<'x> {
         self.header("Message-ID", value.into())
     }
 
+    /// Generate the Message-ID from a hash of the message headers and
+    /// contents rather than randomly, using the given domain as the right
+    /// hand side. Identical messages then share the same Message-ID, which
+    /// lets receivers discard duplicates of a message sent more than once.
+    /// The hash is the first 128 bits of a SHA-256 digest: accidental
+    /// collisions between distinct messages are negligible, and crafting two
+    /// messages with the same Message-ID takes around 2^64 hash computations.
+    /// Has no effect when a Message-ID header is set.
+    pub fn message_id_from_content(mut self, domain: impl Into<Cow<'x, str>>) -> Self {
+        self.message_id_domain = Some(domain.into());
+        self
+    }
+
     /// Set the In-Reply-To header.
     pub fn in_reply_to(self, value: impl Into<MessageId<'x>>) -> Self {
         self.header("In-Reply-To", value.into())
@@ -273,29 +750,110 @@ impl<'x> MessageBuilder<'x> {
         self.header("References", value.into())
     }
 
+    /// Set the References header from a thread's chain of ids, dropping
+    /// repeated ids and trimming long chains to at most `keep` ids: the
+    /// first one, which identifies the thread, and the most recent
+    /// `keep - 1`. No header is added when `keep` is zero or there are no ids.
+    pub fn references_trimmed(self, ids: &[&str], keep: usize) -> Self {
+        let mut references: Vec<Cow<'x, str>> = Vec::with_capacity(ids.len());
+        for id in ids {
+            let id: Cow<'x, str> =
+                Cow::Owned(String::from(strip_angle_brackets(Cow::Borrowed(id))));
+            if !id.is_empty() && !references.contains(&id) {
+                references.push(id);
+            }
+        }
+
+        if keep == 0 || references.is_empty() {
+            return self;
+        }
+        if references.len() > keep {
+            references.drain(1..references.len() - (keep - 1));
+        }
+        self.references(references)
+    }
+
+    /// Set the In-Reply-To, References and Subject headers of a reply to a
+    /// message with the given Message-ID, References and Subject. The
+    /// original Message-ID is appended to the References chain, and the
+    /// subject is prefixed with "Re:" unless it already starts with it.
+    pub fn reply_to_headers<T, U>(
+        self,
+        original_message_id: impl Into<Cow<'x, str>>,
+        original_references: T,
+        original_subject: &str,
+    ) -> Self
+    where
+        T: IntoIterator<Item = U>,
+        U: Into<Cow<'x, str>>,
+    {
+        let message_id = strip_angle_brackets(original_message_id.into());
+        let mut references = original_references
+            .into_iter()
+            .map(|id| strip_angle_brackets(id.into()))
+            .filter(|id| !id.is_empty() && *id != message_id)
+            .collect::<Vec<_>>();
+        references.push(message_id.clone());
+
+        let subject = original_subject.trim();
+        let subject = if subject.is_empty() {
+            String::from("Re:")
+        } else if subject
+            .get(..3)
+            .is_some_and(|prefix| prefix.eq_ignore_ascii_case("re:"))
+        {
+            String::from(subject)
+        } else {
+            format!("Re: {subject}")
+        };
+
+        self.in_reply_to(MessageId::new(message_id))
+            .references(references)
+            .subject(subject)
+    }
+
     /// Set the Sender header.
     pub fn sender(self, value: impl Into<Address<'x>>) -> Self {
         self.header("Sender", value.into())
     }
 
-    /// Set the From header.
+    /// Set the From header. When the From header contains more than one
+    /// mailbox and no Sender header is set, the first mailbox is used as
+    /// the Sender.
     pub fn from(self, value: impl Into<Address<'x>>) -> Self {
         self.header("From", value.into())
     }
 
-    /// Set the To header.
+    /// Set the To header. Calling this method more than once adds the
+    /// addresses to the existing To header.
     pub fn to(self, value: impl Into<Address<'x>>) -> Self {
-        self.header("To", value.into())
+        self.append_address("To", value.into())
     }
 
-    /// Set the Cc header.
+    /// Set the Cc header. Calling this method more than once adds the
+    /// addresses to the existing Cc header.
     pub fn cc(self, value: impl Into<Address<'x>>) -> Self {
-        self.header("Cc", value.into())
+        self.append_address("Cc", value.into())
     }
 
-    /// Set the Bcc header.
+    /// Set the Bcc header. Calling this method more than once adds the
+    /// addresses to the existing Bcc header.
     pub fn bcc(self, value: impl Into<Address<'x>>) -> Self {
-        self.header("Bcc", value.into())
+        self.append_address("Bcc", value.into())
+    }
+
+    fn append_address(mut self, header: &'static str, value: Address<'x>) -> Self {
+        if let Some(HeaderType::Address(address)) = self
+            .headers
+            .iter_mut()
+            .find(|(name, _)| name.eq_ignore_ascii_case(header))
+            .map(|(_, value)| value)
+        {
+            address.append(value);
+            self
+        } else {
+            self.header(header, value)
+        }
     }
 
     /// Set the Reply-To header.
@@ -308,12 +866,132 @@ impl<'x> MessageBuilder<'x> {
         self.header("Subject", value.into())
     }
 
+    /// Set the Subject header of the message, which may be user-supplied.
+    /// Line breaks in the value are handled according to the
+    /// `newline_policy`.
+    pub fn try_subject(self, value: impl Into<Text<'x>>) -> io::Result<Self> {
+        self.try_header("Subject", value.into())
+    }
+
+    /// Set the Content-Language header of the message. Returns an error if
+    /// any of the tags is not a syntactically valid BCP 47 language tag.
+    pub fn content_language(self, tags: &[&str]) -> io::Result<Self> {
+        if tags.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "at least one language tag is required",
+            ));
+        }
+        if let Some(tag) = tags.iter().find(|tag| !is_valid_language_tag(tag)) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid language tag {tag:?}"),
+            ));
+        }
+        Ok(self.header("Content-Language", Text::new(tags.join(", "))))
+    }
+
+    /// Set the Return-Path header to the given address, or to the null
+    /// sender `<>` when the address is empty. The header replaces any
+    /// previous Return-Path and is written before all other headers. The
+    /// address is also stored in `envelope_from`. Returns an error if the
+    /// address is not a single addr-spec.
+    pub fn return_path(mut self, addr: impl Into<Cow<'x, str>>) -> io::Result<Self> {
+        let addr = strip_angle_brackets(addr.into());
+        if !addr.is_empty() && !is_valid_addr_spec(&addr) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid return path {addr:?}"),
+            ));
+        }
+
+        self.headers
+            .retain(|(name, _)| !name.eq_ignore_ascii_case("Return-Path"));
+        self.headers.insert(
+            0,
+            ("Return-Path".into(), Raw::new(format!("<{addr}>")).into()),
+        );
+        self.envelope_from = Some(addr);
+        Ok(self)
+    }
+
+    /// Set the envelope sender (MAIL FROM) of the message, which is written
+    /// as the Return-Path header. Alias of `return_path`.
+    pub fn envelope_from(self, addr: impl Into<Cow<'x, str>>) -> io::Result<Self> {
+        self.return_path(addr)
+    }
+
+    /// Add a block of Resent-* headers for a message being redistributed,
+    /// with a newly generated Resent-Message-ID. Each block is inserted
+    /// before all other headers, so the most recent block comes first.
+    pub fn resent(
+        self,
+        from: impl Into<Address<'x>>,
+        to: impl Into<Address<'x>>,
+        date: impl Into<Date>,
+    ) -> Self {
+        self.resent_with_id(from, to, date, generate_message_id(&default_hostname()))
+    }
+
+    /// Add a block of Resent-* headers for a message being redistributed,
+    /// using the provided Resent-Message-ID.
+    pub fn resent_with_id(
+        mut self,
+        from: impl Into<Address<'x>>,
+        to: impl Into<Address<'x>>,
+        date: impl Into<Date>,
+        message_id: impl Into<MessageId<'x>>,
+    ) -> Self {
+        self.headers.splice(
+            0..0,
+            [
+                ("Resent-Date".into(), date.into().into()),
+                ("Resent-From".into(), from.into().into()),
+                ("Resent-To".into(), to.into().into()),
+                ("Resent-Message-ID".into(), message_id.into().into()),
+            ],
+        );
+        self
+    }
+
     /// Set the Date header. If no Date header is set, one will be generated
-    /// automatically.
+    /// automatically (requires the `std` feature).
     pub fn date(self, value: impl Into<Date>) -> Self {
         self.header("Date", value.into())
     }
 
+    /// Set the User-Agent and X-Mailer headers to the given product string
+    /// followed by this crate's product token (`MAILER_PRODUCT`).
+    pub fn mailer(self, name: impl AsRef<str>) -> Self {
+        self.mailer_with(name, MAILER_PRODUCT)
+    }
+
+    /// Set the User-Agent and X-Mailer headers to the given product string
+    /// followed by a custom product token, or the product string alone when
+    /// empty.
+    pub fn mailer_with(self, name: impl AsRef<str>, product: impl AsRef<str>) -> Self {
+        let (name, product) = (name.as_ref(), product.as_ref());
+        let value = if !product.is_empty() {
+            format!("{} {}", name, product)
+        } else {
+            name.into()
+        };
+        self.header("User-Agent", Text::new(value.clone()))
+            .header("X-Mailer", Text::new(value))
+    }
+
+    /// Set the Auto-Submitted header (RFC 3834), which marks messages sent
+    /// by automated processes.
+    pub fn auto_submitted(self, value: AutoSubmitted) -> Self {
+        self.header("Auto-Submitted", Raw::new(value.as_str()))
+    }
+
+    /// Set the Precedence header, which keeps vacation auto-responders from
+    /// replying to bulk and list mail.
+    pub fn precedence(self, value: Precedence) -> Self {
+        self.header("Precedence", Raw::new(value.as_str()))
+    }
+
     /// Add a custom header.
     pub fn header(
         mut self,
@@ -324,6 +1002,42 @@ impl<'x> MessageBuilder<'x> {
         self
     }
 
+    /// Set a custom header whose value may be user-supplied. Returns an
+    /// error if the name is not a valid field name, or if the value contains
+    /// line breaks and the `newline_policy` is `Reject`.
+    pub fn try_header(
+        self,
+        header: impl Into<Cow<'x, str>>,
+        value: impl Into<HeaderType<'x>>,
+    ) -> io::Result<Self> {
+        let header = header.into();
+        let mut value = value.into();
+        check_header_name(&header)?;
+        value.check_newlines(self.newline_policy)?;
+        Ok(self.header(header, value))
+    }
+
+    /// Set how `try_subject` and `try_header` handle line breaks in header
+    /// values. Defaults to rejecting them.
+    pub fn newline_policy(mut self, value: NewlinePolicy) -> Self {
+        self.newline_policy = value;
+        self
+    }
+
+    /// Add a header whose value is written exactly as given, preserving its
+    /// folding, such as a header copied from an existing message. Returns
+    /// an error if the name is not a valid field name or the value has line
+    /// breaks other than CRLF followed by whitespace.
+    pub fn raw_header(
+        self,
+        header: impl Into<Cow<'x, str>>,
+        value: impl Into<Cow<'x, str>>,
+    ) -> io::Result<Self> {
+        let header = header.into();
+        check_header_name(&header)?;
+        Ok(self.header(header, Folded::new(value)?))
+    }
+
     /// Set custom headers.
     pub fn headers<T, U, V>(mut self, header: T, values: U) -> Self
     where
@@ -356,106 +1070,538 @@ impl<'x> MessageBuilder<'x> {
         self
     }
 
-    /// Add a binary attachment to the message.
+    /// Add an attachment to the message. Text attachments with UTF-8
+    /// contents are labeled with a `charset=utf-8` parameter.
     pub fn attachment(
         mut self,
         content_type: impl Into<ContentType<'x>>,
         filename: impl Into<Cow<'x, str>>,
         value: impl Into<BodyPart<'x>>,
     ) -> Self {
+        let mut part = MimePart::new(content_type, value);
+        part.add_text_charset();
         self.attachments
             .get_or_insert_with(Vec::new)
-            .push(MimePart::new(content_type, value).attachment(filename));
+            .push(part.attachment(filename));
         self
     }
 
-    /// Add an inline binary to the message.
+    /// Add an attachment whose contents are generated by `f` when the message
+    /// is written, so that expensive content is only produced for messages
+    /// that are actually built.
+    #[cfg(feature = "std")]
+    pub fn attachment_lazy(
+        self,
+        content_type: impl Into<ContentType<'x>>,
+        filename: impl Into<Cow<'x, str>>,
+        f: impl FnOnce() -> io::Result<Vec<u8>> + Send + 'x,
+    ) -> Self {
+        self.attachment(content_type, filename, mime::LazyBody::new(f))
+    }
+
+    /// Add an inline binary to the message. When an HTML body is present,
+    /// inline parts are grouped with it in a multipart/related container.
     pub fn inline(
         mut self,
         content_type: impl Into<ContentType<'x>>,
         cid: impl Into<Cow<'x, str>>,
         value: impl Into<BodyPart<'x>>,
     ) -> Self {
-        self.attachments
+        self.inline
             .get_or_insert_with(Vec::new)
             .push(MimePart::new(content_type, value).inline().cid(cid));
         self
     }
 
-    /// Set a custom MIME body structure.
-    pub fn body(mut self, value: MimePart<'x>) -> Self {
-        self.body = Some(value);
+    /// Add an inline binary identified by a Content-Location URL instead of
+    /// a Content-ID, which the HTML body can reference by that (possibly
+    /// relative) URL. When an HTML body is present, it is grouped with it in
+    /// a multipart/related container.
+    pub fn inline_binary_location(
+        mut self,
+        content_type: impl Into<ContentType<'x>>,
+        location: impl Into<Cow<'x, str>>,
+        value: impl Into<BodyPart<'x>>,
+    ) -> Self {
+        self.inline.get_or_insert_with(Vec::new).push(
+            MimePart::new(content_type, value)
+                .inline()
+                .location(location),
+        );
         self
     }
 
-    /// Build the message.
-    pub fn write_to(self, mut output: impl Write) -> io::Result<()> {
-        let mut has_date = false;
-        let mut has_message_id = false;
-        let mut has_mime_version = false;
+    /// Add an inline image to the message using an automatically generated
+    /// Content-ID and return the builder along with its `cid:` URL, which
+    /// can then be referenced from the HTML body. The Content-ID uses the
+    /// domain set with `message_id_from_content`, or the host name.
+    pub fn inline_image(
+        mut self,
+        content_type: impl Into<ContentType<'x>>,
+        value: impl Into<BodyPart<'x>>,
+    ) -> (Self, String) {
+        let url = self.add_inline_image(content_type, value);
+        (self, url)
+    }
 
-        for (header_name, header_value) in &self.headers {
-            if !has_date && header_name == "Date" {
-                has_date = true;
-            } else if !has_message_id && header_name == "Message-ID" {
-                has_message_id = true;
-            } else if !has_mime_version && header_name == "MIME-Version" {
-                has_mime_version = true;
+    fn add_inline_image(
+        &mut self,
+        content_type: impl Into<ContentType<'x>>,
+        value: impl Into<BodyPart<'x>>,
+    ) -> String {
+        let cid = match &self.message_id_domain {
+            Some(domain) => generate_message_id(domain),
+            None => generate_message_id(&default_hostname()),
+        };
+        let url = format!("cid:{}", cid);
+        self.inline
+            .get_or_insert_with(Vec::new)
+            .push(MimePart::new(content_type, value).inline().cid(cid));
+        url
+    }
+
+    /// Replace the remote (http and https) image sources of the HTML body
+    /// with inline images. The `fetcher` is called once for each distinct
+    /// URL and returns the content type and contents of the image. Must be
+    /// called after setting the HTML body.
+    pub fn inline_remote_images<F, C>(mut self, fetcher: F) -> io::Result<Self>
+    where
+        F: Fn(&str) -> io::Result<(C, Vec<u8>)>,
+        C: Into<ContentType<'x>>,
+    {
+        let html = match self.html_body.as_ref().map(|part| &part.contents) {
+            Some(BodyPart::Text(html)) => String::from(html.as_ref()),
+            _ => return Ok(self),
+        };
+        let mut rewritten = String::with_capacity(html.len());
+        let mut fetched: Vec<(&str, String)> = Vec::new();
+        let mut last_pos = 0;
+
+        for (start, end) in find_image_sources(&html) {
+            let url = html[start..end].trim();
+            let is_remote = ["http://", "https://"].iter().any(|scheme| {
+                url.get(..scheme.len())
+                    .is_some_and(|prefix| prefix.eq_ignore_ascii_case(scheme))
+            });
+            if !is_remote {
+                continue;
             }
 
-            output.write_all(header_name.as_bytes())?;
-            output.write_all(b": ")?;
-            header_value.write_header(&mut output, header_name.len() + 2)?;
+            let cid = if let Some((_, cid)) = fetched.iter().find(|(prev, _)| *prev == url) {
+                cid.clone()
+            } else {
+                let (content_type, contents) = fetcher(url)?;
+                let cid = self.add_inline_image(content_type, contents);
+                fetched.push((url, cid.clone()));
+                cid
+            };
+            rewritten.push_str(&html[last_pos..start]);
+            rewritten.push_str(&cid);
+            last_pos = end;
         }
 
-        if !has_message_id {
-            output.write_all(b"Message-ID: ")?;
+        if !fetched.is_empty() {
+            rewritten.push_str(&html[last_pos..]);
+            if let Some(part) = self.html_body.as_mut() {
+                part.contents = BodyPart::Text(rewritten.into());
+            }
+        }
 
-            #[cfg(feature = "gethostname")]
-            generate_message_id_header(
-                &mut output,
-                gethostname::gethostname().to_str().unwrap_or("localhost"),
-            )?;
+        Ok(self)
+    }
 
-            #[cfg(not(feature = "gethostname"))]
-            generate_message_id_header(&mut output, "localhost")?;
+    /// Always wrap the message body in a multipart/mixed container, even
+    /// when the message only has a single body part.
+    pub fn force_multipart(mut self, value: bool) -> Self {
+        self.force_multipart = value;
+        self
+    }
 
-            output.write_all(b"\r\n")?;
-        }
+    /// Override the Content-Type of the container used to group the text and
+    /// HTML bodies, which defaults to multipart/alternative.
+    pub fn alternative_content_type(mut self, value: impl Into<ContentType<'x>>) -> Self {
+        self.alternative_content_type = Some(value.into());
+        self
+    }
 
-        if !has_date {
-            output.write_all(b"Date: ")?;
-            output.write_all(Date::now().to_rfc822().as_bytes())?;
-            output.write_all(b"\r\n")?;
+    /// Override the media type of the top-level multipart container built
+    /// from the message bodies and attachments, such as `multipart/report`.
+    /// The parts are grouped as usual. Returns an error if the type is not
+    /// a `multipart/*` type.
+    pub fn top_level_type(mut self, value: impl Into<Cow<'x, str>>) -> io::Result<Self> {
+        let value = value.into();
+        match value.split_once('/') {
+            Some((top, sub))
+                if top.eq_ignore_ascii_case("multipart")
+                    && !sub.is_empty()
+                    && sub
+                        .bytes()
+                        .all(|ch| ch.is_ascii_alphanumeric() || b"!#$&-^_.+".contains(&ch)) =>
+            {
+                self.top_level_type = Some(value);
+                Ok(self)
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid multipart type {value:?}"),
+            )),
         }
+    }
 
-        if !has_mime_version {
-            output.write_all(b"MIME-Version: 1.0\r\n")?;
-        }
+    /// Set the order of the text and HTML bodies in the multipart/alternative
+    /// container. Defaults to `AltOrder::TextFirst`.
+    pub fn alternative_order(mut self, value: AltOrder) -> Self {
+        self.alternative_order = value;
+        self
+    }
 
-        self.write_body(output)
+    /// Write non-ASCII header values as raw UTF-8 (RFC 6532) instead of
+    /// RFC 2047 encoded-words. Only enable this when the message will be
+    /// sent over an SMTPUTF8 channel.
+    pub fn allow_utf8_headers(mut self, value: bool) -> Self {
+        self.write_options.utf8_headers = value;
+        self
     }
 
-    /// Write the message body without headers.
-    pub fn write_body(self, output: impl Write) -> io::Result<()> {
-        (if let Some(body) = self.body {
-            body
-        } else {
-            match (self.text_body, self.html_body, self.attachments) {
-                (Some(text), Some(html), Some(attachments)) => {
-                    let mut parts = Vec::with_capacity(attachments.len() + 1);
-                    parts.push(MimePart::new("multipart/alternative", vec![text, html]));
-                    parts.extend(attachments);
+    /// Reject parts declared as 7bit that contain non-ASCII bytes instead of
+    /// writing them as-is. See `WriteOptions::strict`.
+    pub fn strict(mut self, value: bool) -> Self {
+        self.write_options.strict = value;
+        self
+    }
 
-                    MimePart::new("multipart/mixed", parts)
-                }
-                (Some(text), Some(html), None) => {
-                    MimePart::new("multipart/alternative", vec![text, html])
-                }
-                (Some(text), None, Some(attachments)) => {
-                    let mut parts = Vec::with_capacity(attachments.len() + 1);
-                    parts.push(text);
-                    parts.extend(attachments);
+    /// Label text parts whose contents are all ASCII as `charset=us-ascii`
+    /// instead of `utf-8`. See `WriteOptions::ascii_charset_label`.
+    pub fn ascii_charset_label(mut self, value: bool) -> Self {
+        self.write_options.ascii_charset_label = value;
+        self
+    }
+
+    /// Write the message using LF line endings instead of CRLF. See
+    /// `WriteOptions::line_ending`.
+    pub fn line_ending(mut self, value: LineEnding) -> Self {
+        self.write_options.line_ending = value;
+        self
+    }
+
+    /// Set the options used when writing the message.
+    pub fn write_options(mut self, value: WriteOptions) -> Self {
+        self.write_options = value;
+        self
+    }
+
+    /// Remove attachments with the same content type and contents as a
+    /// previous attachment when the message is written, keeping the first.
+    pub fn dedupe_attachments(mut self, value: bool) -> Self {
+        self.dedupe_attachments = value;
+        self
+    }
+
+    /// Set a custom MIME body structure.
+    pub fn body(mut self, value: MimePart<'x>) -> Self {
+        self.body = Some(value);
+        self
+    }
+
+    /// Returns an estimate of the size of the message once built, without
+    /// serializing it. The estimate is meant for enforcing size limits
+    /// and errs on the side of over-counting.
+    pub fn estimated_size(&self) -> usize {
+        // Message-ID, Date and MIME-Version headers added on write
+        let mut size = 160;
+        let mut buf = Vec::new();
+
+        for (header_name, header_value) in &self.headers {
+            size += estimated_header_size(header_name, header_value, &mut buf);
+        }
+
+        if let Some(body) = &self.body {
+            size + body.estimated_size()
+        } else {
+            let (num_parts, parts_size) = self
+                .text_body
+                .iter()
+                .chain(self.html_body.iter())
+                .chain(self.inline.iter().flatten())
+                .chain(self.attachments.iter().flatten())
+                .fold((0, 0), |(num_parts, size), part| {
+                    (num_parts + 1, size + part.estimated_size())
+                });
+
+            size + parts_size
+                + if num_parts > 1 || self.force_multipart {
+                    // Up to three nested multipart containers
+                    num_parts * ESTIMATED_BOUNDARY_LEN + 3 * ESTIMATED_CONTAINER_LEN
+                } else {
+                    0
+                }
+        }
+    }
+
+    /// Build the message.
+    pub fn write_to(self, output: impl Write) -> io::Result<()> {
+        self.write_to_with_stats(output).map(|_| ())
+    }
+
+    /// Build the message and return the number of bytes written.
+    pub fn write_to_with_stats(self, output: impl Write) -> io::Result<WriteStats> {
+        self.write_message(output, false)
+    }
+
+    /// Build the message as a message/rfc822 part, to be embedded in another
+    /// message. Embedded messages do not include a MIME-Version header.
+    pub fn into_message_part(self) -> io::Result<MimePart<'static>> {
+        let mut output = Vec::new();
+        self.write_message(&mut output, true)?;
+        let encoding = if output.is_ascii() { "7bit" } else { "8bit" };
+        Ok(MimePart::new_binary_encoded(
+            "message/rfc822",
+            encoding,
+            output,
+        ))
+    }
+
+    /// Sign the message using S/MIME (RFC 8551). The body assembled so far
+    /// is serialized and passed to `signer`, which must return a detached
+    /// CMS signature (DER encoded) over those exact bytes, computed using
+    /// SHA-256. The body is then replaced with a multipart/signed part
+    /// containing the serialized bytes as-is and the signature, so all body
+    /// parts must be set before calling this method.
+    pub fn smime_sign(
+        mut self,
+        signer: impl FnOnce(&[u8]) -> io::Result<Vec<u8>>,
+    ) -> io::Result<Self> {
+        let content = self.serialize_body()?;
+        let signature = signer(&content)?;
+        self.body = Some(MimePart::new(
+            ContentType::new("multipart/signed")
+                .attribute("protocol", "application/pkcs7-signature")
+                .attribute("micalg", "sha-256"),
+            vec![
+                MimePart::from_raw_part(content),
+                MimePart::new(
+                    ContentType::new("application/pkcs7-signature").attribute("name", "smime.p7s"),
+                    signature,
+                )
+                .attachment("smime.p7s"),
+            ],
+        ));
+        Ok(self)
+    }
+
+    /// Encrypt the message using S/MIME (RFC 8551). The body assembled so
+    /// far is serialized and passed to `encryptor`, which must return the
+    /// CMS EnvelopedData (DER encoded) of those bytes. The body is then
+    /// replaced with an application/pkcs7-mime part containing it. Call
+    /// `smime_sign` first to sign and then encrypt the message.
+    pub fn smime_encrypt(
+        mut self,
+        encryptor: impl FnOnce(&[u8]) -> io::Result<Vec<u8>>,
+    ) -> io::Result<Self> {
+        let content = self.serialize_body()?;
+        let enveloped = encryptor(&content)?;
+        self.body = Some(
+            MimePart::new(
+                ContentType::new("application/pkcs7-mime")
+                    .attribute("smime-type", "enveloped-data")
+                    .attribute("name", "smime.p7m"),
+                enveloped,
+            )
+            .attachment("smime.p7m"),
+        );
+        Ok(self)
+    }
+
+    /// Serialize the body assembled so far using CRLF line endings, as
+    /// required for S/MIME processing.
+    fn serialize_body(&mut self) -> io::Result<Vec<u8>> {
+        let options = WriteOptions {
+            line_ending: LineEnding::CrLf,
+            trailing_crlf: None,
+            ..self.write_options.clone()
+        };
+        let mut output = Vec::new();
+        self.take_body().write_part_with(&mut output, &options)?;
+        Ok(output)
+    }
+
+    fn write_message(self, output: impl Write, is_embedded: bool) -> io::Result<WriteStats> {
+        self.write_options.validate()?;
+        let mut output =
+            LineEndingWriter::new(CountingWriter::new(output), self.write_options.line_ending);
+        let mut has_date = false;
+        let mut has_message_id = false;
+        let mut has_mime_version = false;
+        let mut has_sender = false;
+        let mut from = None;
+
+        for (header_name, header_value) in &self.headers {
+            if header_name.eq_ignore_ascii_case("MIME-Version") {
+                // Written once, and only at the top level
+                if has_mime_version || is_embedded {
+                    continue;
+                }
+                has_mime_version = true;
+            } else if !has_date && header_name.eq_ignore_ascii_case("Date") {
+                has_date = true;
+            } else if !has_message_id && header_name.eq_ignore_ascii_case("Message-ID") {
+                has_message_id = true;
+            } else if !has_sender && header_name.eq_ignore_ascii_case("Sender") {
+                has_sender = true;
+            } else if from.is_none() && header_name.eq_ignore_ascii_case("From") {
+                if let HeaderType::Address(address) = header_value {
+                    from = Some(address);
+                }
+            }
+
+            write_header_line(&mut output, header_name, header_value, &self.write_options)?;
+        }
+
+        // RFC 5322 requires a Sender when From contains multiple mailboxes
+        if let Some(sender) = from
+            .filter(|from| !has_sender && from.mailbox_count() > 1)
+            .and_then(|from| from.first_mailbox())
+        {
+            write_header_line(&mut output, "Sender", sender, &self.write_options)?;
+            output.write_all(b"\r\n")?;
+        }
+
+        if !has_message_id {
+            let message_id = if let Some(domain) = &self.message_id_domain {
+                format!("{:032x}@{}", self.content_hash()?, domain)
+            } else {
+                generate_message_id(&default_hostname())
+            };
+            write_header_line(
+                &mut output,
+                "Message-ID",
+                &MessageId::new(message_id),
+                &self.write_options,
+            )?;
+        }
+
+        #[cfg(feature = "std")]
+        if !has_date {
+            write_header_line(&mut output, "Date", &Date::now(), &self.write_options)?;
+        }
+
+        if !has_mime_version && !is_embedded {
+            write_header_line(
+                &mut output,
+                "MIME-Version",
+                &Raw::new("1.0"),
+                &self.write_options,
+            )?;
+        }
+
+        let header_bytes = output.get_ref().count();
+        // The body converts its own line endings
+        output.set_line_ending(LineEnding::CrLf)?;
+        self.write_body(&mut output)?;
+        let output = output.finish()?;
+
+        Ok(WriteStats {
+            total_bytes: output.count(),
+            header_bytes,
+            body_bytes: output.count() - header_bytes,
+        })
+    }
+
+    /// Hash of the headers and body parts, ignoring generated values such
+    /// as boundaries.
+    fn content_hash(&self) -> io::Result<u128> {
+        let mut hasher = ContentHasher::new();
+        for (header_name, header_value) in &self.headers {
+            hasher.update(header_name.as_bytes());
+            hasher.update(b": ");
+            header_value.write_header(&mut hasher, header_name.len() + 2)?;
+        }
+        for part in self
+            .body
+            .iter()
+            .chain(self.text_body.iter())
+            .chain(self.html_body.iter())
+            .chain(self.inline.iter().flatten())
+            .chain(self.attachments.iter().flatten())
+        {
+            hasher.update(b"\r\n");
+            part.hash_contents(&mut hasher)?;
+        }
+        Ok(hasher.finish())
+    }
+
+    /// Write the message body without headers. When no body parts are set,
+    /// an empty text/plain body consisting of a single line break is written.
+    pub fn write_body(mut self, output: impl Write) -> io::Result<()> {
+        self.take_body()
+            .write_part_with(output, &self.write_options)?;
+
+        Ok(())
+    }
+
+    /// Assemble the body parts into the root MIME part that `write_to`
+    /// would write, grouping them in multipart containers as needed. The
+    /// message headers are not included. Parts can then be added to it
+    /// before writing it with `MimePart::write_part` or `body`.
+    pub fn into_mime_part(mut self) -> MimePart<'x> {
+        self.take_body()
+    }
+
+    /// Assemble the body parts into a single MIME part, leaving the builder
+    /// without body parts.
+    fn take_body(&mut self) -> MimePart<'x> {
+        if let Some(body) = self.body.take() {
+            body
+        } else {
+            let mut attachments = self.attachments.take();
+            if self.dedupe_attachments {
+                if let Some(attachments) = &mut attachments {
+                    dedupe_parts(attachments);
+                }
+            }
+            let html_body = match (self.html_body.take(), self.inline.take()) {
+                (Some(html), Some(inline)) => {
+                    let mut parts = Vec::with_capacity(inline.len() + 1);
+                    parts.push(html);
+                    parts.extend(inline);
+                    Some(MimePart::new("multipart/related", parts))
+                }
+                (html, Some(inline)) => {
+                    let attachments = attachments.get_or_insert_with(Vec::new);
+                    attachments.splice(0..0, inline);
+                    html
+                }
+                (html, None) => html,
+            };
+
+            let alternative_content_type = self
+                .alternative_content_type
+                .take()
+                .unwrap_or_else(|| ContentType::new("multipart/alternative"));
+            let alternative_order = self.alternative_order;
+            let alternative = |text, html| {
+                MimePart::new(
+                    alternative_content_type,
+                    match alternative_order {
+                        AltOrder::TextFirst => vec![text, html],
+                        AltOrder::HtmlFirst => vec![html, text],
+                    },
+                )
+            };
+            let is_mixed = attachments.is_some();
+            let root = match (self.text_body.take(), html_body, attachments) {
+                (Some(text), Some(html), Some(attachments)) => {
+                    let mut parts = Vec::with_capacity(attachments.len() + 1);
+                    parts.push(alternative(text, html));
+                    parts.extend(attachments);
+
+                    MimePart::new("multipart/mixed", parts)
+                }
+                (Some(text), Some(html), None) => alternative(text, html),
+                (Some(text), None, Some(attachments)) => {
+                    let mut parts = Vec::with_capacity(attachments.len() + 1);
+                    parts.push(text);
+                    parts.extend(attachments);
                     MimePart::new("multipart/mixed", parts)
                 }
                 (Some(text), None, None) => text,
@@ -468,14 +1614,31 @@ impl<'x> MessageBuilder<'x> {
                 (None, Some(html), None) => html,
                 (None, None, Some(attachments)) => MimePart::new("multipart/mixed", attachments),
                 (None, None, None) => MimePart::new("text/plain", "\n"),
+            };
+
+            let mut root = if self.force_multipart && !is_mixed {
+                MimePart::new("multipart/mixed", vec![root])
+            } else {
+                root
+            };
+
+            if let (Some(top_level_type), BodyPart::Multipart(_)) =
+                (self.top_level_type.take(), &root.contents)
+            {
+                if let Some((_, HeaderType::ContentType(ct))) = root
+                    .headers
+                    .iter_mut()
+                    .find(|(name, _)| name.eq_ignore_ascii_case("Content-Type"))
+                {
+                    ct.c_type = top_level_type;
+                }
             }
-        })
-        .write_part(output)?;
 
-        Ok(())
+            root
+        }
     }
 
-    /// Build message to a Vec<u8>.
+    /// Build message to a `Vec<u8>`.
     pub fn write_to_vec(self) -> io::Result<Vec<u8>> {
         let mut output = Vec::new();
         self.write_to(&mut output)?;
@@ -486,19 +1649,75 @@ impl<'x> MessageBuilder<'x> {
     pub fn write_to_string(self) -> io::Result<String> {
         let mut output = Vec::new();
         self.write_to(&mut output)?;
-        String::from_utf8(output).map_err(|err| io::Error::new(io::ErrorKind::Other, err))
+        String::from_utf8(output).map_err(io::Error::other)
+    }
+
+    /// Build the message and return the header block and the body block
+    /// separately. The header block ends with the CRLF of its last header,
+    /// so the message is the header block followed by a CRLF and the body.
+    /// Both blocks always use CRLF line endings, as required for signing.
+    pub fn render_parts(mut self) -> io::Result<(Vec<u8>, Vec<u8>)> {
+        self.write_options.line_ending = LineEnding::CrLf;
+        let mut output = self.write_to_vec()?;
+        let body = split_body(&output)
+            .map(|pos| {
+                let body = output[pos..].to_vec();
+                output.truncate(pos - 2);
+                body
+            })
+            .unwrap_or_default();
+        Ok((output, body))
+    }
+
+    /// Build the message and return the header block and the body block as
+    /// `render_parts` does, along with the body canonicalized using the DKIM
+    /// "relaxed" body canonicalization algorithm (RFC 6376, Section 3.4.4).
+    /// The canonical body is computed from the returned body, so the hash
+    /// matches the message as transmitted.
+    pub fn render_signed_parts(self) -> io::Result<(Vec<u8>, Vec<u8>, Vec<u8>)> {
+        let (headers, body) = self.render_parts()?;
+        let canonical_body = canonical_body_relaxed(&body);
+        Ok((headers, body, canonical_body))
+    }
+
+    /// Build message to a `Vec<u8>`. Unlike `write_to_vec`, the error
+    /// also covers the UTF-8 check of `into_string`, so both helpers can
+    /// be handled with the same error type.
+    pub fn into_vec(self) -> Result<Vec<u8>, BuildError> {
+        let mut output = Vec::new();
+        self.write_to(&mut output)?;
+        Ok(output)
+    }
+
+    /// Build message to a String, returning `BuildError::Utf8` when a body
+    /// part contains bytes that are not valid UTF-8.
+    pub fn into_string(self) -> Result<String, BuildError> {
+        Ok(String::from_utf8(self.into_vec()?)?)
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use alloc::{
+        borrow::Cow,
+        format,
+        string::{String, ToString},
+        vec,
+        vec::Vec,
+    };
 
-    use mail_parser::MessageParser;
+    use mail_parser::{MessageParser, MimeHeaders};
 
     use crate::{
-        headers::{address::Address, url::URL},
-        mime::MimePart,
-        MessageBuilder,
+        canonical_body_relaxed,
+        encoders::encode::{get_encoding_type_and_len, EncodingType},
+        headers::{
+            address::Address, content_type::ContentType, date::Date, raw::Raw, text::Text,
+            url::URL, Header, HeaderType,
+        },
+        mime::{AttachmentMeta, BodyPart, MediaType, MimePart},
+        AltOrder, AutoSubmitted, BuildError, LineEnding, MessageBuilder, NewlinePolicy, Precedence,
+        WriteOptions,
     };
 
     #[test]
@@ -617,4 +1836,2559 @@ mod tests {
             .unwrap();
         MessageParser::new().parse(&output).unwrap();
     }
+
+    #[test]
+    fn build_inline_image() {
+        let (builder, cid) = MessageBuilder::new()
+            .from(("John Doe", "john@doe.com"))
+            .to("jane@doe.com")
+            .subject("Inline image")
+            .text_body("Text body")
+            .inline_image("image/png", [0, 1, 2, 3, 4, 5].as_ref());
+        assert!(cid.starts_with("cid:"));
+        assert!(cid.contains('@'), "{cid}");
+        let output = builder
+            .html_body(format!("<p>Image: <img src=\"{cid}\"></p>"))
+            .write_to_vec()
+            .unwrap();
+
+        let message = MessageParser::new().parse(&output).unwrap();
+        assert!(message
+            .body_html(0)
+            .unwrap()
+            .contains(&format!("<img src=\"{cid}\">")));
+        let image = message.attachment(0).unwrap();
+        assert_eq!(image.content_id(), Some(&cid[4..]));
+        assert_eq!(image.contents(), &[0, 1, 2, 3, 4, 5]);
+        assert!(String::from_utf8(output)
+            .unwrap()
+            .contains("Content-Type: multipart/related"));
+
+        let (_, cid) = MessageBuilder::new()
+            .message_id_from_content("example.com")
+            .inline_image("image/png", [0, 1, 2].as_ref());
+        assert!(cid.ends_with("@example.com"), "{cid}");
+    }
+
+    #[test]
+    fn build_return_path() {
+        let output = MessageBuilder::new()
+            .header("Return-Path", Address::from("bounces@example.com"))
+            .from("john@doe.com")
+            .text_body("Hello")
+            .write_to_string()
+            .unwrap();
+        assert!(output.starts_with("Return-Path: <bounces@example.com>\r\n"));
+    }
+
+    #[test]
+    fn estimate_size() {
+        let binary = (0..=255u8).cycle().take(100_000).collect::<Vec<_>>();
+        for builder in [
+            MessageBuilder::new()
+                .from(("John Doe", "john@doe.com"))
+                .to("jane@doe.com")
+                .subject("Hello, world!")
+                .text_body("Hello, world!\n".repeat(1000)),
+            MessageBuilder::new()
+                .from(("John Doe", "john@doe.com"))
+                .to("jane@doe.com")
+                .subject("¡Hola, mundo!")
+                .text_body("¡Hola, mundo!\n".repeat(1000))
+                .html_body("<p>안녕하세요 세계</p>".repeat(1000))
+                .inline("image/png", "cid:image", binary.as_slice())
+                .attachment("application/octet-stream", "file.bin", binary.as_slice())
+                .attachment("text/plain", "file.txt", "ハロー・ワールド\n".repeat(1000)),
+            MessageBuilder::new().body(MimePart::new(
+                "multipart/mixed",
+                vec![
+                    MimePart::new("text/plain", "Part A contents go here..."),
+                    MimePart::new("image/jpeg", binary.as_slice()).attachment("image.jpg"),
+                ],
+            )),
+        ] {
+            let estimated_size = builder.estimated_size();
+            let size = builder.write_to_vec().unwrap().len();
+            assert!(
+                estimated_size >= size - size / 50,
+                "estimated {estimated_size}, actual {size}"
+            );
+            assert!(
+                estimated_size <= size + size / 10,
+                "estimated {estimated_size}, actual {size}"
+            );
+        }
+    }
+
+    #[test]
+    fn write_stats() {
+        let mut output = Vec::new();
+        let stats = MessageBuilder::new()
+            .from(("John Doe", "john@doe.com"))
+            .to("jane@doe.com")
+            .subject("Hello, world!")
+            .text_body("Hello, world!")
+            .attachment("image/png", "image.png", [1, 2, 3, 4].as_ref())
+            .write_to_with_stats(&mut output)
+            .unwrap();
+
+        assert_eq!(stats.total_bytes, output.len());
+        assert_eq!(stats.header_bytes + stats.body_bytes, stats.total_bytes);
+        assert!(output[..stats.header_bytes].ends_with(b"MIME-Version: 1.0\r\n"));
+        assert!(output[stats.header_bytes..].starts_with(b"Content-Type: multipart/mixed"));
+    }
+
+    #[test]
+    fn build_into_vec_and_string() {
+        let builder = MessageBuilder::new()
+            .from(("John Doe", "john@doe.com"))
+            .to("jane@doe.com")
+            .subject("Hello, world!")
+            .message_id("hello@world")
+            .date(0u64)
+            .text_body("Hello, world!");
+        let output = builder.clone().into_vec().unwrap();
+        assert!(output.ends_with(b"Hello, world!"));
+        assert_eq!(builder.into_string().unwrap().as_bytes(), output);
+
+        assert!(matches!(
+            MessageBuilder::new()
+                .body(MimePart::raw([0xff, 0xfe].as_ref()))
+                .into_string(),
+            Err(BuildError::Utf8(_))
+        ));
+
+        // Invalid input is returned as an error
+        for builder in [
+            MessageBuilder::new().message_id("bad id\r\nX: y"),
+            MessageBuilder::new().to("bad\r\naddr@x"),
+        ] {
+            let builder = builder.text_body("Hello");
+            assert!(matches!(
+                builder.clone().into_vec(),
+                Err(BuildError::Io(err)) if err.kind() == crate::io::ErrorKind::InvalidInput
+            ));
+            assert!(matches!(builder.into_string(), Err(BuildError::Io(_))));
+        }
+    }
+
+    #[test]
+    fn forward_encoded_attachment() {
+        let encoded = concat!(
+            "JVBERi0xLjQKJcOkw7zDtsOfCjIgMCBvYmoKPDwvTGVuZ3RoIDMgMCBSL0ZpbHRlci9G\r\n",
+            "bGF0ZURlY29kZT4+CnN0cmVhbQp4nDPQM1Qo5ypUMFAw0DMwslAwtTTVMzI3VLAwMdSz\r\n",
+            "UEhJ5UqLz0nOzwUA\r\n"
+        );
+        let output = MessageBuilder::new()
+            .from(("John Doe", "john@doe.com"))
+            .to("jane@doe.com")
+            .subject("Fwd: Report")
+            .body(MimePart::new(
+                "multipart/mixed",
+                vec![
+                    MimePart::new("text/plain", "See attached."),
+                    MimePart::new_binary_encoded("application/pdf", "base64", encoded.as_bytes())
+                        .attachment("report.pdf"),
+                ],
+            ))
+            .write_to_string()
+            .unwrap();
+
+        assert!(output.contains(&format!("filename=\"report.pdf\"\r\n\r\n{encoded}")));
+        assert_eq!(
+            output.matches("Content-Transfer-Encoding: base64").count(),
+            1
+        );
+        let message = MessageParser::new().parse(output.as_bytes()).unwrap();
+        assert!(message
+            .attachment(0)
+            .unwrap()
+            .contents()
+            .starts_with(b"%PDF-1.4"));
+    }
+
+    #[test]
+    fn build_multiple_from() {
+        let output = MessageBuilder::new()
+            .from(vec![
+                ("John Doe", "john.doe@example.com"),
+                ("Jane Doe", "jane.doe@example.com"),
+                ("Antoine de Saint-Exupéry", "antoine@exupery.com"),
+            ])
+            .to("someone@example.com")
+            .text_body("Hello")
+            .write_to_string()
+            .unwrap();
+
+        let from = output
+            .split_once("From: ")
+            .unwrap()
+            .1
+            .split_once("\r\nTo: ")
+            .unwrap()
+            .0;
+        assert!(from.split("\r\n").all(|line| line.len() <= 78), "{from}");
+        assert_eq!(
+            from.replace("\r\n\t", " "),
+            concat!(
+                "\"John Doe\" <john.doe@example.com>, \"Jane Doe\" <jane.doe@example.com>, ",
+                "=?utf-8?Q?Antoine_de_Saint-Exup=C3=A9ry?= <antoine@exupery.com>"
+            )
+        );
+        let parsed = MessageParser::new().parse(output.as_bytes()).unwrap();
+        assert_eq!(
+            parsed
+                .from()
+                .unwrap()
+                .iter()
+                .map(|addr| addr.name().unwrap())
+                .collect::<Vec<_>>(),
+            ["John Doe", "Jane Doe", "Antoine de Saint-Exupéry"]
+        );
+        assert!(output.contains("Sender: \"John Doe\" <john.doe@example.com>\r\n"));
+
+        let output = MessageBuilder::new()
+            .from(vec!["john.doe@example.com", "jane.doe@example.com"])
+            .sender("secretary@example.com")
+            .text_body("Hello")
+            .write_to_string()
+            .unwrap();
+        assert_eq!(output.matches("Sender: ").count(), 1);
+        assert!(output.contains("Sender: <secretary@example.com>\r\n"));
+
+        // Header names are matched case-insensitively
+        let output = MessageBuilder::new()
+            .from(vec!["john.doe@example.com", "jane.doe@example.com"])
+            .header("sender", Address::from("secretary@example.com"))
+            .header("date", Date::new(0))
+            .header("message-id", Raw::new("<id@example.com>"))
+            .text_body("Hello")
+            .write_to_string()
+            .unwrap()
+            .to_ascii_lowercase();
+        for header in ["\nsender: ", "\ndate: ", "\nmessage-id: "] {
+            assert_eq!(output.matches(header).count(), 1, "{output}");
+        }
+    }
+
+    #[test]
+    fn build_force_multipart() {
+        let output = MessageBuilder::new()
+            .from(("John Doe", "john@doe.com"))
+            .to("jane@doe.com")
+            .subject("Hello, world!")
+            .text_body("Hello, world!")
+            .force_multipart(true)
+            .write_to_vec()
+            .unwrap();
+
+        let message = MessageParser::new().parse(&output).unwrap();
+        assert!(message.root_part().is_content_type("multipart", "mixed"));
+        assert_eq!(message.body_text(0).unwrap(), "Hello, world!");
+
+        let output = MessageBuilder::new()
+            .text_body("Hello, world!")
+            .attachment("image/png", "image.png", [1, 2, 3, 4].as_ref())
+            .force_multipart(true)
+            .write_to_string()
+            .unwrap();
+        assert_eq!(output.matches("multipart/mixed").count(), 1);
+    }
+
+    #[test]
+    fn build_content_description() {
+        let output = MessageBuilder::new()
+            .body(MimePart::new(
+                "multipart/mixed",
+                vec![
+                    MimePart::new("text/plain", "Hello"),
+                    MimePart::new("image/png", [1, 2, 3, 4].as_ref())
+                        .attachment("chart.png")
+                        .description("Quarterly chart"),
+                    MimePart::new("image/png", [1, 2, 3, 4].as_ref())
+                        .attachment("graphique.png")
+                        .description("Graphique trimestriel — été"),
+                ],
+            ))
+            .write_to_vec()
+            .unwrap();
+
+        let message = MessageParser::new().parse(&output).unwrap();
+        assert_eq!(
+            message.attachment(0).unwrap().content_description(),
+            Some("Quarterly chart")
+        );
+        assert_eq!(
+            message.attachment(1).unwrap().content_description(),
+            Some("Graphique trimestriel — été")
+        );
+        assert!(String::from_utf8(output)
+            .unwrap()
+            .contains("Content-Description: =?utf-8?"));
+    }
+
+    #[test]
+    fn build_render_parts() {
+        let builder = MessageBuilder::new()
+            .from(("John Doe", "john@doe.com"))
+            .to("jane@doe.com")
+            .subject("Hello, world!")
+            .message_id("hello@world")
+            .date(0u64)
+            .text_body("Hello, world!\n\nSecond paragraph.\n");
+        let (headers, body) = builder.clone().render_parts().unwrap();
+        assert!(headers.ends_with(b"Content-Transfer-Encoding: 7bit\r\n"));
+        assert_eq!(body, b"Hello, world!\r\n\r\nSecond paragraph.\r\n");
+        assert_eq!(
+            [headers, b"\r\n".to_vec(), body].concat(),
+            builder.write_to_vec().unwrap()
+        );
+
+        let (headers, body) = MessageBuilder::new()
+            .text_body("Hello, world!")
+            .attachment("image/png", "image.png", [1, 2, 3, 4].as_ref())
+            .render_parts()
+            .unwrap();
+        assert!(String::from_utf8(headers)
+            .unwrap()
+            .contains("Content-Type: multipart/mixed"));
+        assert!(body.starts_with(b"\r\n--"));
+    }
+
+    #[test]
+    fn build_strict() {
+        // Forcing no encoding on data that is not 7bit always fails
+        for part in [
+            MimePart::new("text/plain", "Señor").force_encoding(EncodingType::None),
+            MimePart::new("application/octet-stream", "Señor".as_bytes())
+                .force_encoding(EncodingType::None),
+            MimePart::new("application/octet-stream", &[0xffu8, 0x00, 0x80][..])
+                .force_encoding(EncodingType::None),
+            MimePart::new("application/octet-stream", &b"a\0b"[..])
+                .force_encoding(EncodingType::None),
+            MimePart::new("application/octet-stream", &b"a\nb"[..])
+                .force_encoding(EncodingType::None),
+            MimePart::new("text/plain", "a".repeat(1000)).force_encoding(EncodingType::None),
+        ] {
+            assert_eq!(
+                MessageBuilder::new()
+                    .body(part)
+                    .write_to_vec()
+                    .unwrap_err()
+                    .kind(),
+                crate::io::ErrorKind::InvalidData
+            );
+        }
+
+        for part in [
+            MimePart::new("text/plain", "Señor").transfer_encoding("7BIT"),
+            MimePart::new_binary_encoded("text/plain", "7bit", "Señor".as_bytes()),
+        ] {
+            let builder = MessageBuilder::new().body(part);
+            assert!(builder.clone().write_to_vec().is_ok());
+            assert_eq!(
+                builder.strict(true).write_to_vec().unwrap_err().kind(),
+                crate::io::ErrorKind::InvalidData
+            );
+        }
+
+        for part in [
+            MimePart::new("text/plain", "Senor").force_encoding(EncodingType::None),
+            MimePart::new("text/plain", "Line\nbreaks\r\n").force_encoding(EncodingType::None),
+            MimePart::new("text/plain", "Señor"),
+            MimePart::new("text/plain", "Señor").transfer_encoding("8bit"),
+        ] {
+            assert!(MessageBuilder::new()
+                .body(part)
+                .strict(true)
+                .write_to_vec()
+                .is_ok());
+        }
+    }
+
+    #[test]
+    fn build_alternative_order() {
+        for (order, expected) in [
+            (AltOrder::TextFirst, ["text/plain", "text/html"]),
+            (AltOrder::HtmlFirst, ["text/html", "text/plain"]),
+        ] {
+            let message = MessageBuilder::new()
+                .text_body("Hello, world!")
+                .html_body("<p>Hello, world!</p>")
+                .alternative_order(order)
+                .write_to_vec()
+                .unwrap();
+            let message = MessageParser::new().parse(&message).unwrap();
+            let types = message.parts[1..]
+                .iter()
+                .map(|part| {
+                    let ct = part.content_type().unwrap();
+                    format!("{}/{}", ct.ctype(), ct.subtype().unwrap())
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(types, expected);
+        }
+    }
+
+    #[test]
+    fn build_alternative_content_type() {
+        let output = MessageBuilder::new()
+            .text_body("Hello, world!")
+            .html_body("<p>Hello, world!</p>")
+            .alternative_content_type(
+                ContentType::new("multipart/x-mixed-replace").attribute("x-variant", "test"),
+            )
+            .write_to_string()
+            .unwrap();
+        assert!(
+            output.contains("Content-Type: multipart/x-mixed-replace; x-variant=\"test\";"),
+            "{output}"
+        );
+        assert!(!output.contains("multipart/alternative"));
+    }
+
+    #[test]
+    fn build_top_level_type() {
+        let output = MessageBuilder::new()
+            .text_body("Delivery failed")
+            .attachment("message/delivery-status", "status", "Reporting-MTA: dns; a")
+            .top_level_type("multipart/report")
+            .unwrap()
+            .write_to_vec()
+            .unwrap();
+        let message = MessageParser::new().parse(&output).unwrap();
+        let ct = message.content_type().unwrap();
+        assert_eq!(ct.ctype(), "multipart");
+        assert_eq!(ct.subtype(), Some("report"));
+        assert_eq!(message.parts.len(), 3);
+        assert_eq!(message.body_text(0).as_deref(), Some("Delivery failed"));
+
+        // Nested containers keep their own type
+        let output = MessageBuilder::new()
+            .text_body("Hello")
+            .html_body("<p>Hello</p>")
+            .force_multipart(true)
+            .top_level_type("multipart/signed")
+            .unwrap()
+            .write_to_string()
+            .unwrap();
+        assert!(
+            output.contains("Content-Type: multipart/signed;"),
+            "{output}"
+        );
+        assert!(
+            output.contains("Content-Type: multipart/alternative;"),
+            "{output}"
+        );
+
+        // Single part messages are left untouched
+        let output = MessageBuilder::new()
+            .text_body("Hello")
+            .top_level_type("multipart/mixed")
+            .unwrap()
+            .write_to_string()
+            .unwrap();
+        assert!(!output.contains("multipart/"), "{output}");
+
+        for value in ["text/plain", "multipart", "multipart/", "multipart/a b"] {
+            assert_eq!(
+                MessageBuilder::new()
+                    .top_level_type(value)
+                    .unwrap_err()
+                    .kind(),
+                crate::io::ErrorKind::InvalidInput,
+                "{value}"
+            );
+        }
+    }
+
+    #[test]
+    fn build_auto_submitted() {
+        let output = MessageBuilder::new()
+            .from("mailer-daemon@example.com")
+            .auto_submitted(AutoSubmitted::AutoReplied)
+            .precedence(Precedence::Bulk)
+            .text_body("Out of office")
+            .write_to_vec()
+            .unwrap();
+        let message = MessageParser::new().parse(&output).unwrap();
+        assert_eq!(
+            message.header_raw("Auto-Submitted").map(str::trim),
+            Some("auto-replied")
+        );
+        assert_eq!(
+            message.header_raw("Precedence").map(str::trim),
+            Some("bulk")
+        );
+
+        for (value, expected) in [
+            (AutoSubmitted::AutoGenerated, "auto-generated"),
+            (AutoSubmitted::No, "no"),
+        ] {
+            let output = MessageBuilder::new()
+                .auto_submitted(value)
+                .write_to_string()
+                .unwrap();
+            assert!(
+                output.contains(&format!("Auto-Submitted: {expected}\r\n")),
+                "{output}"
+            );
+        }
+        for (value, expected) in [(Precedence::List, "list"), (Precedence::Junk, "junk")] {
+            let output = MessageBuilder::new()
+                .precedence(value)
+                .write_to_string()
+                .unwrap();
+            assert!(
+                output.contains(&format!("Precedence: {expected}\r\n")),
+                "{output}"
+            );
+        }
+    }
+
+    #[test]
+    fn build_mailer() {
+        for (builder, expected) in [
+            (
+                MessageBuilder::new().mailer("Acme Notifier/2.1"),
+                format!(
+                    "Acme Notifier/2.1 mail-builder/{}",
+                    env!("CARGO_PKG_VERSION")
+                ),
+            ),
+            (
+                MessageBuilder::new().mailer_with("Acme Notifier/2.1", "rust-mail"),
+                "Acme Notifier/2.1 rust-mail".to_string(),
+            ),
+            (
+                MessageBuilder::new().mailer_with("Acme Notifier/2.1", ""),
+                "Acme Notifier/2.1".to_string(),
+            ),
+        ] {
+            let output = builder.text_body("Hello").write_to_string().unwrap();
+            assert!(
+                output.starts_with(&format!(
+                    "User-Agent: {expected}\r\nX-Mailer: {expected}\r\n"
+                )),
+                "{output}"
+            );
+        }
+    }
+
+    #[test]
+    fn build_from_template() {
+        let template = MessageBuilder::new()
+            .from(("John Doe", "john@doe.com"))
+            .subject("Monthly report")
+            .text_body("Please find the report attached.")
+            .attachment("application/pdf", "report.pdf", [1, 2, 3, 4].as_ref());
+
+        for recipient in ["jane@doe.com", "james@doe.com"] {
+            let message = template.clone().to(recipient).write_to_vec().unwrap();
+            let message = MessageParser::new().parse(&message).unwrap();
+            assert_eq!(
+                message.to().unwrap().first().unwrap().address(),
+                Some(recipient)
+            );
+            assert_eq!(message.attachment_count(), 1);
+        }
+        assert!(template.headers.iter().all(|(name, _)| name != "To"));
+    }
+
+    #[test]
+    fn build_canonical_body_relaxed() {
+        let builder = MessageBuilder::new()
+            .from(("John Doe", "john@doe.com"))
+            .to("jane@doe.com")
+            .subject("Hello, world!")
+            .text_body("Hello,   world!\n\n\tSecond\t line\n\n\n\n");
+        let (_, _, canonical_body) = builder.clone().render_signed_parts().unwrap();
+        assert_eq!(canonical_body, b"Hello, world!\r\n\r\n Second line\r\n");
+
+        // Multipart boundaries are random, the canonical body must match the
+        // rendered one
+        let (headers, body, canonical_body) = builder
+            .html_body("<p>Hello,   world!</p>")
+            .render_signed_parts()
+            .unwrap();
+        assert_eq!(canonical_body, canonical_body_relaxed(&body));
+        let boundary = String::from_utf8_lossy(&body)
+            .lines()
+            .find(|line| line.starts_with("--"))
+            .unwrap()
+            .to_string();
+        assert!(String::from_utf8_lossy(&headers).contains(&boundary[2..]));
+
+        for (body, expected) in [
+            ("", ""),
+            ("\r\n\r\n", ""),
+            ("a  \r\n", "a\r\n"),
+            ("a", "a\r\n"),
+            ("\r\na\r\n \r\n\r\n", "\r\na\r\n"),
+        ] {
+            assert_eq!(
+                String::from_utf8(canonical_body_relaxed(body.as_bytes())).unwrap(),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn build_references_from_owned_ids() {
+        let prior_ids = vec![
+            "first@example.com".to_string(),
+            "second@example.com".to_string(),
+        ];
+
+        let message = MessageBuilder::new()
+            .in_reply_to(prior_ids.last().unwrap().clone())
+            .references(prior_ids.as_slice())
+            .write_to_string()
+            .unwrap();
+        assert!(message.contains("In-Reply-To: <second@example.com>\r\n"));
+        assert!(message.contains("References: <first@example.com> <second@example.com>\r\n"));
+
+        let message = MessageBuilder::new()
+            .references(prior_ids)
+            .write_to_string()
+            .unwrap();
+        assert!(message.contains("References: <first@example.com> <second@example.com>\r\n"));
+    }
+
+    #[test]
+    fn build_utf8_headers() {
+        let builder = MessageBuilder::new()
+            .from(("Jöhn Döe", "john@doe.com"))
+            .to(vec![("Jäne Döe", "jane@doe.com"), ("Bob", "bob@doe.com")])
+            .subject("¡Hola, señor! Ünïcödé sübject")
+            .text_body("Hello");
+
+        let message = builder
+            .clone()
+            .write_options(WriteOptions::new().utf8_headers(true))
+            .write_to_string()
+            .unwrap();
+        assert!(!message.contains("=?utf-8?"), "{message}");
+        assert!(message.contains("Subject: ¡Hola, señor! Ünïcödé sübject\r\n"));
+        assert!(message.contains("From: \"Jöhn Döe\" <john@doe.com>\r\n"));
+        assert!(message.contains("To: \"Jäne Döe\" <jane@doe.com>, \"Bob\" <bob@doe.com>\r\n"));
+
+        let message = builder.write_to_string().unwrap();
+        assert!(message.contains("Subject: =?utf-8?"), "{message}");
+        assert!(!message.contains("Jöhn"));
+    }
+
+    #[test]
+    fn build_allow_utf8_headers() {
+        let builder = MessageBuilder::new()
+            .from(("Jöhn Döe", "john@doe.com"))
+            .subject("Résumé")
+            .text_body("Hello")
+            .attachment("application/pdf", "résumé.pdf", b"%PDF".as_ref());
+
+        let message = builder
+            .clone()
+            .allow_utf8_headers(true)
+            .write_to_string()
+            .unwrap();
+        assert!(!message.contains("=?utf-8?"), "{message}");
+        assert!(message.contains("Subject: Résumé\r\n"), "{message}");
+        assert!(
+            message.contains("From: \"Jöhn Döe\" <john@doe.com>\r\n"),
+            "{message}"
+        );
+        assert!(message.contains("filename=\"résumé.pdf\""), "{message}");
+
+        let parsed = MessageParser::new().parse(message.as_bytes()).unwrap();
+        assert_eq!(
+            parsed.attachment(0).unwrap().attachment_name(),
+            Some("résumé.pdf")
+        );
+
+        let message = builder
+            .header("X-Note", Text::new("línea\r\nInjected: x"))
+            .allow_utf8_headers(true)
+            .write_to_string()
+            .unwrap();
+        assert!(!message.contains("\r\nInjected"), "{message}");
+    }
+
+    #[test]
+    fn build_normalized_line_endings() {
+        for (suffix, encoding) in [
+            ("", "7bit"),
+            (" and some more plain text, señor", "quoted-printable"),
+            ("ñññññññññññññññ", "base64"),
+        ] {
+            for is_binary in [false, true] {
+                let text = format!("a\nb\r\nc\rd{suffix}");
+                let body = if is_binary {
+                    MimePart::new("text/plain", text.into_bytes())
+                } else {
+                    MimePart::new("text/plain", text)
+                };
+                let message = MessageBuilder::new().body(body).write_to_vec().unwrap();
+                let message_str = String::from_utf8_lossy(&message);
+                assert!(
+                    message_str.contains(&format!("Content-Transfer-Encoding: {encoding}\r\n")),
+                    "{message_str}"
+                );
+
+                let message = MessageParser::new().parse(&message).unwrap();
+                assert_eq!(
+                    message.body_text(0).unwrap(),
+                    format!("a\r\nb\r\nc\r\nd{suffix}"),
+                    "{encoding} binary={is_binary}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn build_lazy_attachment() {
+        use core::sync::atomic::{AtomicBool, Ordering};
+
+        let invoked = AtomicBool::new(false);
+        let builder = MessageBuilder::new()
+            .from(("John Doe", "john@doe.com"))
+            .to("jane@doe.com")
+            .text_body("See attached")
+            .attachment_lazy("application/pdf", "report.pdf", || {
+                invoked.store(true, Ordering::SeqCst);
+                Ok(b"%PDF-1.7 rendered".to_vec())
+            });
+        assert!(builder.estimated_size() > 0);
+        assert!(!invoked.load(Ordering::SeqCst));
+
+        let message = builder.clone().write_to_vec().unwrap();
+        assert!(invoked.load(Ordering::SeqCst));
+        let message = MessageParser::new().parse(&message).unwrap();
+        assert_eq!(
+            message.attachment(0).unwrap().contents(),
+            b"%PDF-1.7 rendered"
+        );
+
+        // Clones share the generated contents
+        invoked.store(false, Ordering::SeqCst);
+        builder.write_to_vec().unwrap();
+        assert!(!invoked.load(Ordering::SeqCst));
+
+        let err = MessageBuilder::new()
+            .attachment_lazy("application/pdf", "report.pdf", || {
+                Err(crate::io::Error::other("render failed"))
+            })
+            .write_to_vec()
+            .unwrap_err();
+        assert_eq!(err.to_string(), "render failed");
+    }
+
+    #[test]
+    fn build_date_offset() {
+        for (date, offset, expected) in [
+            (0, 0, "Thu, 1 Jan 1970 00:00:00 +0000"),
+            // New York, daylight saving and standard time
+            (1_751_378_400, -4 * 3600, "Tue, 1 Jul 2025 10:00:00 -0400"),
+            (1_736_953_200, -5 * 3600, "Wed, 15 Jan 2025 10:00:00 -0500"),
+            (1_751_378_400, 19_800, "Tue, 1 Jul 2025 19:30:00 +0530"),
+            (0, -3600, "Wed, 31 Dec 1969 23:00:00 -0100"),
+            (0, -86340, "Wed, 31 Dec 1969 00:01:00 -2359"),
+        ] {
+            let date = Date::with_offset(date, offset).unwrap();
+            assert_eq!(date.to_rfc822(), expected);
+            let output = MessageBuilder::new()
+                .date(date.clone())
+                .write_to_vec()
+                .unwrap();
+            assert_eq!(
+                MessageParser::new()
+                    .parse(&output)
+                    .unwrap()
+                    .date()
+                    .unwrap()
+                    .to_timestamp(),
+                date.date
+            );
+        }
+
+        // Offsets must be whole minutes under 24 hours
+        for offset in [100 * 3600, -86400, 86400, -30, 90] {
+            assert_eq!(
+                Date::with_offset(0, offset).unwrap_err().kind(),
+                crate::io::ErrorKind::InvalidInput
+            );
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "chrono-tz")]
+    fn build_date_chrono_tz() {
+        use chrono::TimeZone;
+        use chrono_tz::America::New_York;
+
+        for ((year, month, day), expected) in [
+            ((2025, 7, 1), "Tue, 1 Jul 2025 10:00:00 -0400"),
+            ((2025, 1, 15), "Wed, 15 Jan 2025 10:00:00 -0500"),
+        ] {
+            let datetime = New_York
+                .with_ymd_and_hms(year, month, day, 10, 0, 0)
+                .unwrap();
+            let output = MessageBuilder::new()
+                .date(datetime)
+                .text_body("Hello")
+                .write_to_string()
+                .unwrap();
+            assert!(
+                output.contains(&format!("Date: {expected}\r\n")),
+                "{output}"
+            );
+            assert_eq!(
+                MessageParser::new()
+                    .parse(output.as_bytes())
+                    .unwrap()
+                    .date()
+                    .unwrap()
+                    .to_timestamp(),
+                datetime.timestamp()
+            );
+        }
+
+        // Local mean time (-04:56:02) is rounded to the nearest minute
+        let datetime = New_York.with_ymd_and_hms(1880, 1, 1, 0, 0, 0).unwrap();
+        assert_eq!(
+            Date::from(datetime).to_rfc822(),
+            "Thu, 1 Jan 1880 00:00:02 -0456"
+        );
+    }
+
+    #[test]
+    fn build_trailing_crlf() {
+        let builder = MessageBuilder::new()
+            .from("john@doe.com")
+            .text_body("Hello")
+            .attachment("text/plain", "a.txt", "Attached");
+
+        let message = builder.clone().write_to_string().unwrap();
+        assert!(message.ends_with("--\r\n"), "{message}");
+
+        let message = builder
+            .write_options(WriteOptions::new().trailing_crlf(false))
+            .write_to_string()
+            .unwrap();
+        assert!(message.ends_with("--"), "{message}");
+        assert!(MessageParser::new()
+            .parse(message.as_bytes())
+            .unwrap()
+            .attachment(0)
+            .is_some());
+
+        let builder = MessageBuilder::new()
+            .from("john@doe.com")
+            .text_body("Hello");
+        let message = builder.clone().write_to_string().unwrap();
+        assert!(message.ends_with("\r\n\r\nHello"), "{message}");
+        let message = builder
+            .clone()
+            .write_options(WriteOptions::new().trailing_crlf(false))
+            .write_to_string()
+            .unwrap();
+        assert!(message.ends_with("\r\n\r\nHello"), "{message}");
+        let message = builder
+            .write_options(WriteOptions::new().trailing_crlf(true))
+            .write_to_string()
+            .unwrap();
+        assert!(message.ends_with("\r\n\r\nHello\r\n"), "{message}");
+    }
+
+    #[test]
+    fn build_appended_recipients() {
+        let mut builder = MessageBuilder::new().from("john@doe.com");
+        for recipient in ["jane@doe.com", "bob@doe.com"] {
+            builder = builder.to(recipient);
+        }
+        let message = builder
+            .to(vec![("Alice", "alice@doe.com"), ("Carol", "carol@doe.com")])
+            .cc("dave@doe.com")
+            .cc(("Eve", "eve@doe.com"))
+            .text_body("Hello")
+            .write_to_string()
+            .unwrap();
+        assert_eq!(message.matches("\r\nTo: ").count(), 1, "{message}");
+        assert_eq!(message.matches("\r\nCc: ").count(), 1, "{message}");
+        assert!(message.contains("Cc: <dave@doe.com>, \"Eve\" <eve@doe.com>\r\n"));
+
+        let message = MessageParser::new().parse(message.as_bytes()).unwrap();
+        assert_eq!(
+            message
+                .to()
+                .unwrap()
+                .iter()
+                .map(|addr| addr.address.as_deref().unwrap())
+                .collect::<Vec<_>>(),
+            [
+                "jane@doe.com",
+                "bob@doe.com",
+                "alice@doe.com",
+                "carol@doe.com"
+            ]
+        );
+    }
+
+    #[test]
+    fn build_prefer_quoted_printable() {
+        let text = "Привет, мир! Это тестовое сообщение.";
+
+        for (part, encoding) in [
+            (MimePart::new("text/plain", text), "base64"),
+            (
+                MimePart::new("text/plain", text).prefer_quoted_printable(),
+                "quoted-printable",
+            ),
+            (
+                MimePart::new("text/plain", text.as_bytes()).prefer_quoted_printable(),
+                "quoted-printable",
+            ),
+            (
+                MimePart::new("application/octet-stream", text.as_bytes())
+                    .prefer_quoted_printable(),
+                "base64",
+            ),
+        ] {
+            let estimated_size = part.estimated_size();
+            let mut output = Vec::new();
+            let size = part.write_part(&mut output).unwrap();
+            assert!(estimated_size >= size);
+
+            let output = String::from_utf8(output).unwrap();
+            assert!(
+                output.contains(&format!("Content-Transfer-Encoding: {encoding}\r\n")),
+                "{output}"
+            );
+        }
+
+        let message = MessageBuilder::new()
+            .body(MimePart::new("text/plain", text).prefer_quoted_printable())
+            .write_to_vec()
+            .unwrap();
+        assert_eq!(
+            MessageParser::new()
+                .parse(&message)
+                .unwrap()
+                .body_text(0)
+                .unwrap(),
+            text
+        );
+    }
+
+    #[test]
+    fn build_content_language() {
+        let message = MessageBuilder::new()
+            .content_language(&["en-US", "fr-CA"])
+            .unwrap()
+            .text_body("Hello")
+            .write_to_string()
+            .unwrap();
+        assert!(message.contains("Content-Language: en-US, fr-CA\r\n"));
+
+        for tags in [
+            &["en US"][..],
+            &["en-US\r\nBcc: x@y.com"],
+            &["en--US"],
+            &["1en"],
+            &["en-verylongsubtag"],
+            &[""],
+            &[],
+        ] {
+            assert_eq!(
+                MessageBuilder::new()
+                    .content_language(tags)
+                    .unwrap_err()
+                    .kind(),
+                crate::io::ErrorKind::InvalidInput,
+                "{tags:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn build_forced_encoding_in_custom_tree() {
+        let body = MimePart::new(
+            "multipart/mixed",
+            vec![
+                MimePart::new(
+                    "multipart/alternative",
+                    vec![
+                        MimePart::new("text/plain", "Hello, world!")
+                            .force_encoding(EncodingType::Base64),
+                        MimePart::new("text/html", "<p>Hello, world!</p>"),
+                    ],
+                ),
+                MimePart::new("application/octet-stream", &b"plain ascii"[..])
+                    .force_encoding(EncodingType::QuotedPrintable(true))
+                    .attachment("data.bin"),
+            ],
+        );
+        let estimated_size = body.estimated_size();
+        let mut output = Vec::new();
+        let size = body.write_part(&mut output).unwrap();
+        assert!(estimated_size >= size);
+
+        let message = MessageBuilder::new()
+            .body(MimePart::raw(output))
+            .write_to_vec()
+            .unwrap();
+        let message = MessageParser::new().parse(&message).unwrap();
+        let text = message.text_part(0).unwrap();
+        assert_eq!(text.content_transfer_encoding(), Some("base64"));
+        assert_eq!(message.body_text(0).unwrap(), "Hello, world!");
+        assert_eq!(
+            message.html_part(0).unwrap().content_transfer_encoding(),
+            Some("7bit")
+        );
+        let attachment = message.attachment(0).unwrap();
+        assert_eq!(
+            attachment.content_transfer_encoding(),
+            Some("quoted-printable")
+        );
+        assert_eq!(attachment.contents(), b"plain ascii");
+    }
+
+    #[test]
+    fn build_dedupe_attachments() {
+        let builder = MessageBuilder::new()
+            .from("john@doe.com")
+            .text_body("Newsletter")
+            .attachment("application/pdf", "issue.pdf", &b"%PDF issue 42"[..])
+            .attachment("application/pdf", "issue-copy.pdf", &b"%PDF issue 42"[..])
+            .attachment(
+                "application/octet-stream",
+                "issue.bin",
+                &b"%PDF issue 42"[..],
+            )
+            .attachment("application/pdf", "issue-43.pdf", &b"%PDF issue 43"[..]);
+
+        let count_attachments = |builder: MessageBuilder<'_>| {
+            let message = builder.write_to_vec().unwrap();
+            MessageParser::new()
+                .parse(&message)
+                .unwrap()
+                .attachments()
+                .map(|part| part.attachment_name().unwrap().to_string())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(count_attachments(builder.clone()).len(), 4);
+        assert_eq!(
+            count_attachments(builder.dedupe_attachments(true)),
+            ["issue.pdf", "issue.bin", "issue-43.pdf"]
+        );
+    }
+
+    #[test]
+    fn build_resent() {
+        let message = MessageBuilder::new()
+            .header("Received", Raw::new("from mx.example.com by example.org"))
+            .from("john@doe.com")
+            .to("jane@doe.com")
+            .subject("Original")
+            .resent_with_id("jane@doe.com", "bob@doe.com", 0u64, "first@doe.com")
+            .resent(
+                "bob@doe.com",
+                vec!["alice@doe.com", "carol@doe.com"],
+                86400u64,
+            )
+            .text_body("Hello")
+            .write_to_string()
+            .unwrap();
+
+        let header_names = message
+            .split("\r\n\r\n")
+            .next()
+            .unwrap()
+            .split("\r\n")
+            .filter_map(|line| line.split_once(':').map(|(name, _)| name))
+            .filter(|name| !name.starts_with('\t'))
+            .take(9)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            header_names,
+            [
+                "Resent-Date",
+                "Resent-From",
+                "Resent-To",
+                "Resent-Message-ID",
+                "Resent-Date",
+                "Resent-From",
+                "Resent-To",
+                "Resent-Message-ID",
+                "Received",
+            ]
+        );
+        assert!(message.starts_with("Resent-Date: Fri, 2 Jan 1970 00:00:00 +0000\r\n"));
+        assert!(message.contains(concat!(
+            "Resent-From: <bob@doe.com>\r\n",
+            "Resent-To: <alice@doe.com>, <carol@doe.com>\r\n",
+            "Resent-Message-ID: <"
+        )));
+        assert!(message.contains(concat!(
+            "Resent-From: <jane@doe.com>\r\n",
+            "Resent-To: <bob@doe.com>\r\n",
+            "Resent-Message-ID: <first@doe.com>\r\n"
+        )));
+    }
+
+    #[test]
+    fn build_max_depth() {
+        let nested = |depth: usize| {
+            let mut part = MimePart::new("text/plain", "Hello");
+            for _ in 0..depth {
+                part = MimePart::new("multipart/mixed", vec![part]);
+            }
+            part
+        };
+
+        let err = MessageBuilder::new()
+            .body(nested(1000))
+            .write_to_vec()
+            .unwrap_err();
+        assert_eq!(err.kind(), crate::io::ErrorKind::InvalidInput);
+
+        assert!(MessageBuilder::new()
+            .body(nested(100))
+            .write_to_vec()
+            .is_ok());
+        assert!(MessageBuilder::new()
+            .body(nested(10))
+            .write_options(WriteOptions::new().max_depth(10))
+            .write_to_vec()
+            .is_ok());
+        assert!(MessageBuilder::new()
+            .body(nested(11))
+            .write_options(WriteOptions::new().max_depth(10))
+            .write_to_vec()
+            .is_err());
+    }
+
+    #[test]
+    fn build_message_id_from_content() {
+        let builder = MessageBuilder::new()
+            .from("john@doe.com")
+            .to("jane@doe.com")
+            .subject("Invoice #42")
+            .date(0u64)
+            .text_body("Please find attached your invoice.")
+            .attachment("application/pdf", "invoice.pdf", &b"%PDF invoice 42"[..])
+            .message_id_from_content("example.com");
+        let message_id = |builder: MessageBuilder<'_>| {
+            let message = builder.write_to_vec().unwrap();
+            MessageParser::new()
+                .parse(&message)
+                .unwrap()
+                .message_id()
+                .unwrap()
+                .to_string()
+        };
+
+        let id = message_id(builder.clone());
+        assert!(id.ends_with("@example.com"), "{id}");
+        assert_eq!(id.len(), 32 + "@example.com".len());
+        assert_eq!(id, message_id(builder.clone()));
+        assert_ne!(id, message_id(builder.clone().subject("Invoice #43")));
+        assert_eq!(
+            message_id(builder.message_id("custom@example.com")),
+            "custom@example.com"
+        );
+    }
+
+    #[test]
+    fn build_header_separator() {
+        let message = MessageBuilder::new()
+            .from(("John Doe", "john@doe.com"))
+            .to("jane@doe.com")
+            .subject(concat!(
+                "A rather long subject line that needs to be folded ",
+                "at least once when written to a message"
+            ))
+            .text_body("Hello")
+            .attachment("image/png", "image.png", &b"not really a png"[..])
+            .write_options(WriteOptions::new().header_separator(":").fold_indent(" "))
+            .write_to_string()
+            .unwrap();
+
+        let mut num_headers = 0;
+        let mut num_folds = 0;
+        for line in message.split("\r\n") {
+            if let Some((_, value)) = line.split_once(':').filter(|(name, _)| {
+                !name.is_empty()
+                    && name
+                        .bytes()
+                        .all(|ch| ch.is_ascii_alphanumeric() || ch == b'-')
+            }) {
+                assert!(!value.starts_with(' '), "{line}");
+                num_headers += 1;
+            } else if line.starts_with(' ') {
+                num_folds += 1;
+            }
+            assert!(!line.starts_with('\t'), "{line}");
+        }
+        // To, From, Subject, Message-ID, Date (std only), MIME-Version,
+        // multipart Content-Type, two Content-Type, two CTE and
+        // Content-Disposition
+        let num_expected = if cfg!(feature = "std") { 12 } else { 11 };
+        assert_eq!(num_headers, num_expected, "{message}");
+        assert!(num_folds > 0);
+
+        let parsed = MessageParser::new().parse(message.as_bytes()).unwrap();
+        assert_eq!(
+            parsed.subject().unwrap(),
+            concat!(
+                "A rather long subject line that needs to be folded ",
+                "at least once when written to a message"
+            )
+        );
+        assert_eq!(
+            parsed.attachment(0).unwrap().contents(),
+            b"not really a png"
+        );
+
+        for options in [
+            WriteOptions::new().header_separator(" : "),
+            WriteOptions::new().header_separator(":x"),
+            WriteOptions::new().fold_indent(""),
+            WriteOptions::new().fold_indent("x"),
+        ] {
+            assert!(MessageBuilder::new()
+                .text_body("Hello")
+                .write_options(options)
+                .write_to_vec()
+                .is_err());
+        }
+    }
+
+    #[test]
+    fn build_reply_to_headers() {
+        for (references, subject, expected_references, expected_subject) in [
+            (
+                vec!["<a@example.com>", "b@example.com"],
+                "Hello",
+                "<a@example.com> <b@example.com> <c@example.com>",
+                "Re: Hello",
+            ),
+            (vec![], "RE: Hello", "<c@example.com>", "RE: Hello"),
+            (
+                vec!["c@example.com"],
+                "re:Hello",
+                "<c@example.com>",
+                "re:Hello",
+            ),
+            (vec![], "", "<c@example.com>", "Re:"),
+        ] {
+            let message = MessageBuilder::new()
+                .from("jane@doe.com")
+                .to("john@doe.com")
+                .reply_to_headers("<c@example.com>", references, subject)
+                .text_body("Hi")
+                .write_to_string()
+                .unwrap();
+            assert!(
+                message.contains("In-Reply-To: <c@example.com>\r\n"),
+                "{message}"
+            );
+            assert!(
+                message.contains(&format!("References: {expected_references}\r\n")),
+                "{message}"
+            );
+            assert!(
+                message.contains(&format!("Subject: {expected_subject}\r\n")),
+                "{message}"
+            );
+        }
+    }
+
+    #[test]
+    fn build_mime_version() {
+        let count = |message: &str| {
+            message
+                .to_ascii_lowercase()
+                .matches("mime-version:")
+                .count()
+        };
+
+        let message = MessageBuilder::new()
+            .text_body("Hello")
+            .write_to_string()
+            .unwrap();
+        assert_eq!(count(&message), 1);
+        assert!(message.contains("MIME-Version: 1.0\r\n"));
+
+        let message = MessageBuilder::new()
+            .header("mime-version", Raw::new("1.0"))
+            .header("MIME-Version", Raw::new("1.0"))
+            .text_body("Hello")
+            .write_to_string()
+            .unwrap();
+        assert_eq!(count(&message), 1, "{message}");
+        assert!(message.contains("mime-version: 1.0\r\n"));
+
+        let child = MessageBuilder::new()
+            .from("jane@doe.com")
+            .subject("Original")
+            .header("MIME-Version", Raw::new("1.0"))
+            .text_body("Original message")
+            .into_message_part()
+            .unwrap();
+        let message = MessageBuilder::new()
+            .from("john@doe.com")
+            .subject("Fwd: Original")
+            .body(MimePart::new(
+                "multipart/mixed",
+                vec![MimePart::new("text/plain", "See attached"), child],
+            ))
+            .write_to_string()
+            .unwrap();
+        assert_eq!(count(&message), 1, "{message}");
+
+        let message = MessageParser::new().parse(message.as_bytes()).unwrap();
+        let nested = message.attachment(0).unwrap().message().unwrap();
+        assert_eq!(nested.subject(), Some("Original"));
+        assert_eq!(nested.body_text(0).unwrap(), "Original message");
+    }
+
+    #[test]
+    fn build_multipart_attribute_order() {
+        let parts = || {
+            vec![
+                MimePart::new("text/plain", "Delivery failed"),
+                MimePart::new("message/delivery-status", "Reporting-MTA: dns; mx.doe.com"),
+            ]
+        };
+
+        let message = MessageBuilder::new()
+            .body(MimePart::new(
+                ContentType::new("multipart/report").attribute("report-type", "delivery-status"),
+                parts(),
+            ))
+            .write_to_string()
+            .unwrap();
+        assert!(
+            message.contains(
+                "Content-Type: multipart/report; report-type=\"delivery-status\";\r\n\tboundary="
+            ),
+            "{message}"
+        );
+
+        let message = MessageBuilder::new()
+            .body(MimePart::new(
+                ContentType::new("multipart/report")
+                    .attribute("boundary", "report-boundary")
+                    .attribute("report-type", "delivery-status")
+                    .attribute("x-extra", "1"),
+                parts(),
+            ))
+            .write_to_string()
+            .unwrap();
+        assert!(
+            message.contains(concat!(
+                "Content-Type: multipart/report; boundary=\"report-boundary\";\r\n",
+                "\treport-type=\"delivery-status\"; x-extra=\"1\"\r\n"
+            )),
+            "{message}"
+        );
+        assert!(message.contains("\r\n--report-boundary--"), "{message}");
+    }
+
+    #[test]
+    fn build_return_path_helper() {
+        let builder = MessageBuilder::new()
+            .header("Return-Path", Raw::new("<old@example.com>"))
+            .from("john@doe.com")
+            .text_body("Hello")
+            .return_path("bounces+jane=doe.com@example.com")
+            .unwrap();
+        assert_eq!(
+            builder.envelope_from.as_deref(),
+            Some("bounces+jane=doe.com@example.com")
+        );
+        let message = builder.write_to_string().unwrap();
+        assert!(
+            message.starts_with("Return-Path: <bounces+jane=doe.com@example.com>\r\nFrom: "),
+            "{message}"
+        );
+        assert!(!message.contains("old@example.com"));
+
+        for (addr, expected) in [
+            ("", "<>"),
+            ("<>", "<>"),
+            ("<john@doe.com>", "<john@doe.com>"),
+            ("\"john doe\"@doe.com", "<\"john doe\"@doe.com>"),
+            ("john@[192.0.2.1]", "<john@[192.0.2.1]>"),
+        ] {
+            let message = MessageBuilder::new()
+                .envelope_from(addr)
+                .unwrap()
+                .write_to_string()
+                .unwrap();
+            assert!(
+                message.starts_with(&format!("Return-Path: {expected}\r\n")),
+                "{message}"
+            );
+        }
+
+        for addr in [
+            "john",
+            "john@",
+            "@doe.com",
+            "john..doe@doe.com",
+            "john@doe.com, jane@doe.com",
+            "John <john@doe.com>",
+            "john@doe.com\r\nBcc: x@y.com",
+        ] {
+            assert!(MessageBuilder::new().return_path(addr).is_err(), "{addr}");
+        }
+    }
+
+    #[test]
+    fn build_boundary() {
+        let message = MessageBuilder::new()
+            .body(
+                MimePart::new(
+                    "multipart/mixed",
+                    vec![
+                        MimePart::new("text/plain", "Hello"),
+                        MimePart::new(
+                            "multipart/alternative",
+                            vec![MimePart::new("text/plain", "Hi")],
+                        )
+                        .boundary("inner=_boundary")
+                        .unwrap()
+                        .boundary("inner'(boundary) 2")
+                        .unwrap(),
+                    ],
+                )
+                .boundary("outer")
+                .unwrap(),
+            )
+            .write_to_string()
+            .unwrap();
+        assert!(message.contains("boundary=\"outer\"\r\n"), "{message}");
+        assert!(
+            message.contains("boundary=\"inner'(boundary) 2\"\r\n"),
+            "{message}"
+        );
+        assert_eq!(message.matches("boundary=").count(), 2);
+        assert!(
+            message.contains("\r\n--inner'(boundary) 2--\r\n"),
+            "{message}"
+        );
+        assert!(message.ends_with("\r\n--outer--\r\n"), "{message}");
+
+        for boundary in [
+            "",
+            "trailing ",
+            "semi;colon",
+            "quote\"",
+            "\r\n",
+            &"a".repeat(71),
+        ] {
+            assert!(
+                MimePart::new("multipart/mixed", Vec::<MimePart>::new())
+                    .boundary(boundary)
+                    .is_err(),
+                "{boundary:?}"
+            );
+        }
+        assert!(MimePart::new("multipart/mixed", Vec::<MimePart>::new())
+            .boundary("a".repeat(70))
+            .is_ok());
+        assert!(MimePart::raw("raw").boundary("raw").is_err());
+    }
+
+    #[test]
+    fn build_calendar() {
+        let ics = concat!(
+            "BEGIN:VCALENDAR\r\nMETHOD:REQUEST\r\nBEGIN:VEVENT\r\n",
+            "SUMMARY:Reunión de planificación\r\nEND:VEVENT\r\nEND:VCALENDAR\r\n"
+        );
+        let message = MessageBuilder::new()
+            .from("john@doe.com")
+            .body(MimePart::new(
+                "multipart/mixed",
+                vec![
+                    MimePart::new("text/plain", "You are invited"),
+                    MimePart::new_calendar("REQUEST", ics),
+                    MimePart::new_calendar("REQUEST", ics).attachment("invite.ics"),
+                ],
+            ))
+            .write_to_string()
+            .unwrap();
+        assert_eq!(
+            message
+                .matches("Content-Type: text/calendar; method=\"REQUEST\"; charset=\"utf-8\"\r\n")
+                .count(),
+            2,
+            "{message}"
+        );
+
+        let message = MessageParser::new().parse(message.as_bytes()).unwrap();
+        for (pos, name) in [(2, None), (3, Some("invite.ics"))] {
+            let part = &message.parts[pos];
+            assert_eq!(
+                part.content_type().unwrap().attribute("method"),
+                Some("REQUEST")
+            );
+            assert_eq!(part.text_contents(), Some(ics));
+            assert_eq!(part.attachment_name(), name);
+        }
+    }
+
+    #[test]
+    fn build_chained() {
+        let message = MessageBuilder::new()
+            .from(("John Doe", "john@doe.com"))
+            .sender("secretary@doe.com")
+            .reply_to("replies@doe.com")
+            .to("jane@doe.com")
+            .cc("james@doe.com")
+            .bcc("hidden@doe.com")
+            .subject("Chained")
+            .date(1_700_000_000_i64)
+            .header("X-Chained", Raw::new("yes"))
+            .text_body("Hello")
+            .html_body("<p>Hello <img src=\"cid:logo\"></p>")
+            .inline("image/png", "logo", [0u8, 1, 2].as_ref())
+            .attachment("text/plain", "notes.txt", "Notes")
+            .write_to_vec()
+            .unwrap();
+        let message = MessageParser::new().parse(&message).unwrap();
+        assert_eq!(message.subject(), Some("Chained"));
+        assert_eq!(message.body_text(0).unwrap(), "Hello");
+        assert_eq!(
+            message.attachment(1).unwrap().attachment_name(),
+            Some("notes.txt")
+        );
+
+        let message = MessageBuilder::new()
+            .from("john@doe.com")
+            .body(MimePart::new("text/plain", "Custom body"))
+            .write_to_string()
+            .unwrap();
+        assert!(message.ends_with("\r\n\r\nCustom body"), "{message}");
+    }
+
+    #[test]
+    fn build_text_attachment_charset() {
+        let message = MessageBuilder::new()
+            .text_body("See attached")
+            .attachment("text/plain", "notes.txt", "Notas del señor".as_bytes())
+            .attachment(
+                ContentType::new("text/csv").attribute("header", "present"),
+                "data.csv",
+                "año,mes".to_string(),
+            )
+            .attachment("text/plain", "latin1.txt", b"se\xf1or".as_ref())
+            .attachment(
+                ContentType::new("text/plain").attribute("charset", "us-ascii"),
+                "ascii.txt",
+                b"plain".as_ref(),
+            )
+            .write_to_string()
+            .unwrap();
+        assert!(
+            message.contains("Content-Type: text/plain; charset=\"utf-8\"\r\nContent-Transfer-Encoding: quoted-printable\r\nContent-Disposition: attachment; filename=\"notes.txt\""),
+            "{message}"
+        );
+        assert!(
+            message.contains("Content-Type: text/csv; header=\"present\"; charset=\"utf-8\""),
+            "{message}"
+        );
+        assert!(
+            message.contains("Content-Type: text/plain\r\nContent-Transfer-Encoding: quoted-printable\r\nContent-Disposition: attachment; filename=\"latin1.txt\""),
+            "{message}"
+        );
+        assert_eq!(message.matches("charset=").count(), 4, "{message}");
+
+        let message = MessageParser::new().parse(message.as_bytes()).unwrap();
+        let attachment = message.attachment(0).unwrap();
+        assert_eq!(attachment.attachment_name(), Some("notes.txt"));
+        assert_eq!(attachment.text_contents(), Some("Notas del señor"));
+        assert_eq!(
+            attachment.content_transfer_encoding(),
+            Some("quoted-printable")
+        );
+    }
+
+    #[test]
+    fn build_references_trimmed() {
+        let ids = (1..=30)
+            .map(|n| format!("<message-{n}@example.com>"))
+            .collect::<Vec<_>>();
+        let mut ids = ids.iter().map(|id| id.as_str()).collect::<Vec<_>>();
+        ids.insert(5, "message-1@example.com");
+        ids.push("<message-29@example.com>");
+
+        let message = MessageBuilder::new()
+            .references_trimmed(&ids, 4)
+            .write_to_string()
+            .unwrap();
+        assert!(
+            message.contains(concat!(
+                "References: <message-1@example.com> <message-28@example.com>\r\n",
+                "\t<message-29@example.com> <message-30@example.com>\r\n"
+            )),
+            "{message}"
+        );
+
+        let message = MessageBuilder::new()
+            .references_trimmed(&ids, 100)
+            .write_to_vec()
+            .unwrap();
+        let message = MessageParser::new().parse(&message).unwrap();
+        let references = message.references().as_text_list().unwrap();
+        assert_eq!(references.len(), 30);
+        assert_eq!(references[0], "message-1@example.com");
+        assert_eq!(references[29], "message-30@example.com");
+
+        for (ids, keep) in [(&["a@b"][..], 0), (&[][..], 5), (&["<>"][..], 5)] {
+            let message = MessageBuilder::new()
+                .references_trimmed(ids, keep)
+                .write_to_string()
+                .unwrap();
+            assert!(!message.contains("References:"), "{message}");
+        }
+
+        let message = MessageBuilder::new()
+            .references_trimmed(&ids, 1)
+            .write_to_string()
+            .unwrap();
+        assert!(message.contains("References: <message-1@example.com>\r\n"));
+    }
+
+    #[test]
+    fn build_part_header_order() {
+        fn header_names<'x>(message: &'x str, marker: &str) -> Vec<&'x str> {
+            let start = message.find(marker).unwrap();
+            let start = message[..start].rfind("\r\n--").unwrap();
+            let end = start + message[start..].find("\r\n\r\n").unwrap();
+            message[start..end]
+                .split("\r\n")
+                .filter_map(|line| line.split_once(": ").map(|(name, _)| name))
+                .collect()
+        }
+
+        let message = MessageBuilder::new()
+            .body(MimePart::new(
+                "multipart/mixed",
+                vec![
+                    MimePart::new("text/plain", "Señor, this is a text part")
+                        .header("X-Part", Raw::new("text"))
+                        .language("es")
+                        .inline()
+                        .cid("text@doe.com"),
+                    MimePart::new("application/pdf", b"%PDF-1.4".as_ref())
+                        .description("Report")
+                        .cid("report@doe.com")
+                        .attachment("report.pdf"),
+                    MimePart::new(
+                        "multipart/alternative",
+                        vec![MimePart::new("text/plain", "Alt")],
+                    )
+                    .header("X-Multipart", Raw::new("alt")),
+                ],
+            ))
+            .write_to_string()
+            .unwrap();
+
+        assert_eq!(
+            header_names(&message, "text@doe.com"),
+            [
+                "Content-Type",
+                "Content-Transfer-Encoding",
+                "Content-Disposition",
+                "Content-ID",
+                "X-Part",
+                "Content-Language"
+            ]
+        );
+        assert!(message.contains(
+            "Content-Transfer-Encoding: quoted-printable\r\nContent-Disposition: inline"
+        ));
+        assert_eq!(
+            header_names(&message, "report@doe.com"),
+            [
+                "Content-Type",
+                "Content-Transfer-Encoding",
+                "Content-Disposition",
+                "Content-ID",
+                "Content-Description"
+            ]
+        );
+        assert!(message
+            .contains("Content-Transfer-Encoding: base64\r\nContent-Disposition: attachment"));
+        assert_eq!(
+            header_names(&message, "X-Multipart"),
+            ["Content-Type", "X-Multipart"]
+        );
+    }
+
+    #[test]
+    fn build_ascii_charset_label() {
+        let builder = MessageBuilder::new()
+            .text_body("Hello, world!")
+            .html_body("<p>¡Hola, mundo!</p>")
+            .attachment("text/plain", "notes.txt", b"Plain notes".as_ref())
+            .attachment(
+                ContentType::new("text/plain").attribute("charset", "iso-8859-1"),
+                "latin1.txt",
+                b"Latin notes".as_ref(),
+            );
+
+        let message = builder.clone().write_to_string().unwrap();
+        assert_eq!(message.matches("charset=\"utf-8\"").count(), 3);
+        assert!(!message.contains("us-ascii"));
+
+        let message = builder.ascii_charset_label(true).write_to_string().unwrap();
+        assert!(
+            message.contains("Content-Type: text/plain; charset=\"us-ascii\"\r\nContent-Transfer-Encoding: 7bit\r\n\r\nHello, world!"),
+            "{message}"
+        );
+        assert!(
+            message.contains("Content-Type: text/html; charset=\"utf-8\""),
+            "{message}"
+        );
+        assert_eq!(
+            message.matches("charset=\"us-ascii\"").count(),
+            2,
+            "{message}"
+        );
+        assert!(message.contains("charset=\"iso-8859-1\""));
+    }
+
+    #[test]
+    fn build_raw_header() {
+        let received = concat!(
+            "from mx.example.com (mx.example.com [192.0.2.1])\r\n",
+            "\tby mail.doe.com with ESMTPS id 4A2B3C; Tue, 1 Jul 2025 10:00:00 +0000"
+        );
+        let message = MessageBuilder::new()
+            .raw_header("Received", received)
+            .unwrap()
+            .raw_header("X-Long", "x".repeat(100))
+            .unwrap()
+            .text_body("Hello")
+            .write_to_string()
+            .unwrap();
+        assert!(message.starts_with(&format!(
+            "Received: {received}\r\nX-Long: {}\r\n",
+            "x".repeat(100)
+        )));
+
+        for (name, value) in [
+            ("X-Test", "bare\nline feed"),
+            ("X-Test", "injected\r\nBcc: victim@example.com"),
+            ("X-Test", "value\r\n"),
+            ("X Test", "value"),
+            ("X-Test:", "value"),
+            ("", "value"),
+        ] {
+            assert!(
+                MessageBuilder::new().raw_header(name, value).is_err(),
+                "{name:?}: {value:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn build_empty_groups() {
+        let message = MessageBuilder::new()
+            .from("john@doe.com")
+            .to(("hidden-recipients", Vec::<(&str, &str)>::new()))
+            .cc(Vec::<&str>::new())
+            .text_body("Hello")
+            .write_to_string()
+            .unwrap();
+        assert!(message.contains("To: hidden-recipients:;\r\n"), "{message}");
+        assert!(!message.contains("Cc:"), "{message}");
+
+        let message = MessageParser::new().parse(message.as_bytes()).unwrap();
+        let group = &message.to().unwrap().as_group().unwrap()[0];
+        assert_eq!(group.name.as_deref(), Some("hidden-recipients"));
+        assert!(group.addresses.is_empty());
+    }
+
+    #[test]
+    fn build_attachment_meta() {
+        let contents = b"%PDF-1.4 report".as_ref();
+        let message = MessageBuilder::new()
+            .body(MimePart::new(
+                "multipart/mixed",
+                vec![
+                    MimePart::new("text/plain", "See attached"),
+                    MimePart::new("application/pdf", contents).attachment_with(
+                        "report.pdf",
+                        AttachmentMeta {
+                            size: Some(contents.len()),
+                            created: Some(Date::new(1_700_000_000)),
+                            modified: Some(Date::new(1_700_086_400)),
+                        },
+                    ),
+                    MimePart::new("application/pdf", contents)
+                        .attachment_with("plain.pdf", AttachmentMeta::default()),
+                ],
+            ))
+            .write_to_string()
+            .unwrap();
+        assert!(
+            message.contains(concat!(
+                "Content-Disposition: attachment; filename=\"report.pdf\";\r\n",
+                "\tcreation-date=\"Tue, 14 Nov 2023 22:13:20 +0000\";\r\n",
+                "\tmodification-date=\"Wed, 15 Nov 2023 22:13:20 +0000\"; size=\"15\"\r\n"
+            )),
+            "{message}"
+        );
+        assert!(message.contains("Content-Disposition: attachment; filename=\"plain.pdf\"\r\n"));
+
+        let message = MessageParser::new().parse(message.as_bytes()).unwrap();
+        let disposition = message
+            .attachment(0)
+            .unwrap()
+            .content_disposition()
+            .unwrap();
+        assert_eq!(disposition.attribute("size"), Some("15"));
+        assert_eq!(
+            disposition.attribute("creation-date"),
+            Some("Tue, 14 Nov 2023 22:13:20 +0000")
+        );
+    }
+
+    #[test]
+    fn build_auto_encoding_not_7bit() {
+        // Unterminated long lines and bare CRs are not 7bit
+        for (input, is_body) in [
+            ("x".repeat(2000), true),
+            ("y".repeat(1500), false),
+            ("a\rb".to_string(), false),
+        ] {
+            assert_ne!(
+                get_encoding_type_and_len(input.as_bytes(), false, is_body).0,
+                EncodingType::None,
+                "{input:?}"
+            );
+        }
+        assert_eq!(
+            get_encoding_type_and_len("z".repeat(997).as_bytes(), false, true).0,
+            EncodingType::None
+        );
+
+        let message = MessageBuilder::new()
+            .text_body("x".repeat(2000))
+            .write_to_string()
+            .unwrap();
+        let message = MessageParser::new().parse(message.as_bytes()).unwrap();
+        assert_eq!(message.body_text(0).unwrap(), "x".repeat(2000));
+
+        for contents in ["y".repeat(1500), "a\rb".to_string()] {
+            let message = MessageBuilder::new()
+                .attachment("text/plain", "a.txt", contents.as_str())
+                .write_to_string()
+                .unwrap();
+            assert!(message.lines().all(|line| line.len() <= 998));
+            let message = MessageParser::new().parse(message.as_bytes()).unwrap();
+            assert_eq!(
+                message.attachment(0).unwrap().contents(),
+                contents.as_bytes()
+            );
+        }
+
+        let mut output = Vec::new();
+        MimePart::new("text/plain", "z".repeat(1500))
+            .write_part(&mut output)
+            .unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("Content-Transfer-Encoding: quoted-printable\r\n"));
+        assert!(output.lines().all(|line| line.len() <= 998));
+    }
+
+    #[test]
+    fn build_control_characters() {
+        for input in [&b"a\0b"[..], b"bell\x07", b"form\x0cfeed\r\n"] {
+            for (is_inline, is_body) in [(false, true), (false, false), (true, false)] {
+                assert_ne!(
+                    get_encoding_type_and_len(input, is_inline, is_body).0,
+                    EncodingType::None,
+                    "{input:?}"
+                );
+            }
+        }
+        assert_eq!(
+            get_encoding_type_and_len(b"tab\tand\r\nlines\r\n", false, true).0,
+            EncodingType::None
+        );
+
+        let message = MessageBuilder::new()
+            .subject("a\0b")
+            .text_body("Hello\0world")
+            .write_to_vec()
+            .unwrap();
+        assert!(!message.contains(&0), "{message:?}");
+        let message_str = String::from_utf8_lossy(&message);
+        assert!(
+            message_str
+                .contains("Content-Transfer-Encoding: quoted-printable\r\n\r\nHello=00world"),
+            "{message_str}"
+        );
+        let parsed = MessageParser::new().parse(&message).unwrap();
+        assert_eq!(parsed.body_text(0).unwrap(), "Hello\0world");
+        assert_eq!(parsed.subject(), Some("a\0b"));
+    }
+
+    #[test]
+    fn build_empty() {
+        for (input, is_inline, is_body) in [
+            (&b""[..], false, true),
+            (b"", false, false),
+            (b"", true, false),
+        ] {
+            let (encoding, len) = get_encoding_type_and_len(input, is_inline, is_body);
+            assert_eq!(encoding, EncodingType::None);
+            assert_eq!(len, 0);
+        }
+
+        for header in [
+            HeaderType::from(Text::new("")),
+            Raw::new("").into(),
+            URL::new_list(Vec::<&str>::new().into_iter()).into(),
+            Address::new_list(vec![]).into(),
+        ] {
+            let mut output = Vec::new();
+            header.write_header(&mut output, 9).unwrap();
+        }
+
+        let message = MessageBuilder::new()
+            .from("john@doe.com")
+            .text_body("")
+            .write_to_string()
+            .unwrap();
+        assert!(
+            message.ends_with("Content-Transfer-Encoding: 7bit\r\n\r\n"),
+            "{message}"
+        );
+        let parsed = MessageParser::new().parse(message.as_bytes()).unwrap();
+        assert_eq!(parsed.body_text(0).unwrap(), "");
+
+        for builder in [
+            MessageBuilder::new(),
+            MessageBuilder::new().html_body(""),
+            MessageBuilder::new().text_body("").html_body(""),
+            MessageBuilder::new().attachment("application/octet-stream", "empty.bin", &b""[..]),
+        ] {
+            let message = builder.write_to_vec().unwrap();
+            assert!(
+                MessageParser::new().parse(&message).is_some(),
+                "{}",
+                String::from_utf8_lossy(&message)
+            );
+        }
+    }
+
+    #[test]
+    fn build_inline_remote_images() {
+        use core::cell::RefCell;
+
+        let fetched = RefCell::new(Vec::new());
+        let fetcher = |url: &str| {
+            fetched.borrow_mut().push(url.to_string());
+            Ok(("image/png", url.as_bytes().to_vec()))
+        };
+
+        let message = MessageBuilder::new()
+            .from("john@doe.com")
+            .html_body(concat!(
+                "<p><IMG alt=\"logo\" SRC=\"https://example.com/logo.png\"></p>",
+                "<p><img src='http://example.com/photo.jpg'/></p>",
+                "<p><img src=\"https://example.com/logo.png\"></p>",
+                "<p><img src=\"cid:existing\"><img src=\"data:image/png;base64,AA==\"></p>",
+                "<p><a href=\"https://example.com/\">src=\"https://example.com/x\"</a></p>"
+            ))
+            .inline_remote_images(fetcher)
+            .unwrap()
+            .write_to_vec()
+            .unwrap();
+
+        assert_eq!(
+            *fetched.borrow(),
+            [
+                "https://example.com/logo.png",
+                "http://example.com/photo.jpg"
+            ]
+        );
+
+        let message = MessageParser::new().parse(&message).unwrap();
+        let html = message.body_html(0).unwrap();
+        assert!(
+            !html.contains("src=\"https://example.com/logo.png\""),
+            "{html}"
+        );
+        assert!(!html.contains("http://example.com/photo.jpg"), "{html}");
+        assert!(html.contains("src=\"cid:existing\""), "{html}");
+        assert!(html.contains("data:image/png"), "{html}");
+        assert!(html.contains("src=\"https://example.com/x\""), "{html}");
+
+        let cids = message
+            .parts
+            .iter()
+            .filter_map(|part| part.content_id())
+            .collect::<Vec<_>>();
+        assert_eq!(cids.len(), 2);
+        let mut uses = cids
+            .iter()
+            .map(|cid| html.matches(&format!("cid:{cid}")).count())
+            .collect::<Vec<_>>();
+        uses.sort_unstable();
+        assert_eq!(uses, [1, 2]);
+        assert_eq!(
+            message.parts[0].content_type().unwrap().subtype(),
+            Some("related")
+        );
+
+        assert!(MessageBuilder::new()
+            .html_body("<img src=\"https://example.com/missing.png\">")
+            .inline_remote_images(|_| {
+                Err::<(&str, Vec<u8>), _>(crate::io::Error::other("not found"))
+            })
+            .is_err());
+    }
+
+    #[test]
+    fn build_message_id_injection() {
+        for builder in [
+            MessageBuilder::new().message_id("a\r\nInjected: x"),
+            MessageBuilder::new().body(
+                MimePart::new("image/png", b"data".as_ref())
+                    .inline()
+                    .cid("a\r\nInjected: x"),
+            ),
+        ] {
+            let mut output = Vec::new();
+            assert!(builder.write_to(&mut output).is_err());
+            assert!(!String::from_utf8_lossy(&output).contains("\r\nInjected"));
+        }
+
+        let message = MessageBuilder::new()
+            .message_id("<already@bracketed>")
+            .body(
+                MimePart::new("image/png", b"data".as_ref())
+                    .inline()
+                    .cid("<logo@example.com>"),
+            )
+            .write_to_string()
+            .unwrap();
+        assert!(
+            message.contains("Message-ID: <already@bracketed>\r\n"),
+            "{message}"
+        );
+        assert!(
+            message.contains("Content-ID: <logo@example.com>\r\n"),
+            "{message}"
+        );
+    }
+
+    #[test]
+    fn build_owned_bodies() {
+        fn build(name: &str) -> MessageBuilder<'static> {
+            MessageBuilder::new()
+                .text_body(format!("Hello, {name}!"))
+                .html_body(Cow::Owned(format!("<p>Hello, {name}!</p>")))
+                .attachment(
+                    "text/plain",
+                    format!("{name}.txt"),
+                    format!("Notes for {name}"),
+                )
+                .attachment(
+                    "application/octet-stream",
+                    Cow::Owned(format!("{name}.bin")),
+                    Cow::<[u8]>::Owned(name.as_bytes().to_vec()),
+                )
+        }
+
+        let message = build("Jane").write_to_vec().unwrap();
+        let message = MessageParser::new().parse(&message).unwrap();
+        assert_eq!(message.body_text(0).unwrap(), "Hello, Jane!");
+        assert_eq!(message.body_html(0).unwrap(), "<p>Hello, Jane!</p>");
+        assert_eq!(
+            message.attachment(0).unwrap().attachment_name(),
+            Some("Jane.txt")
+        );
+        assert_eq!(message.attachment(1).unwrap().contents(), b"Jane");
+    }
+
+    #[test]
+    fn build_custom_encoding() {
+        // Reverses the contents, standing in for a compression scheme
+        let reverse = |input: &[u8], output: &mut dyn crate::io::Write| {
+            let reversed = input.iter().rev().copied().collect::<Vec<_>>();
+            output.write_all(&reversed)?;
+            Ok(reversed.len())
+        };
+        let message = MessageBuilder::new()
+            .body(MimePart::new(
+                "multipart/mixed",
+                vec![
+                    MimePart::new("text/plain", "Hello, world!"),
+                    MimePart::new("application/octet-stream", &b"abc123"[..])
+                        .custom_encoding("x-reverse", reverse)
+                        .attachment("data.bin"),
+                ],
+            ))
+            .write_to_string()
+            .unwrap();
+        assert!(
+            message.contains(concat!(
+                "Content-Type: application/octet-stream\r\n",
+                "Content-Transfer-Encoding: x-reverse\r\n",
+                "Content-Disposition: attachment; filename=\"data.bin\"\r\n",
+                "\r\n",
+                "321cba\r\n"
+            )),
+            "{message}"
+        );
+
+        let message = MessageParser::new().parse(&message).unwrap();
+        assert_eq!(message.body_text(0).unwrap(), "Hello, world!");
+        assert_eq!(
+            message.attachment(0).unwrap().content_transfer_encoding(),
+            Some("x-reverse")
+        );
+    }
+
+    #[test]
+    fn build_multipart_from_iter() {
+        let files = [
+            ("a.txt", "First"),
+            ("b.txt", "Second"),
+            ("c.log", "Skipped"),
+        ];
+        let mut root =
+            MimePart::new_multipart_from_iter(
+                "multipart/mixed",
+                [MimePart::new("text/plain", "Hello")].into_iter().chain(
+                    files.iter().filter(|(name, _)| name.ends_with(".txt")).map(
+                        |(name, contents)| MimePart::new("text/plain", *contents).attachment(*name),
+                    ),
+                ),
+            );
+        root.extend([MimePart::new("text/plain", "Third").attachment("d.txt")]);
+
+        let mut text = MimePart::new("text/plain", "Not a container");
+        text.extend([MimePart::new("text/plain", "Ignored")]);
+        assert!(matches!(text.contents, BodyPart::Text(_)));
+
+        let output = MessageBuilder::new().body(root).write_to_vec().unwrap();
+        let message = MessageParser::new().parse(&output).unwrap();
+        assert_eq!(message.body_text(0).unwrap(), "Hello");
+        assert_eq!(
+            message
+                .attachments()
+                .map(|part| part.attachment_name().unwrap())
+                .collect::<Vec<_>>(),
+            ["a.txt", "b.txt", "d.txt"]
+        );
+    }
+
+    #[test]
+    fn build_inline_binary_location() {
+        let output = MessageBuilder::new()
+            .text_body("Logo")
+            .html_body("<img src=\"images/logo.png\">")
+            .inline_binary_location("image/png", "images/logo.png", &b"\x89PNG"[..])
+            .attachment("text/plain", "notes.txt", "Notes")
+            .write_to_vec()
+            .unwrap();
+        let message = MessageParser::new().parse(&output).unwrap();
+
+        let related = message
+            .parts
+            .iter()
+            .find(|part| {
+                part.content_type()
+                    .is_some_and(|ct| ct.subtype() == Some("related"))
+            })
+            .unwrap();
+        let mail_parser::PartType::Multipart(children) = &related.body else {
+            panic!("expected multipart/related");
+        };
+        let image = &message.parts[children[1]];
+        assert_eq!(image.content_location(), Some("images/logo.png"));
+        assert!(image.content_id().is_none());
+        assert_eq!(image.contents(), b"\x89PNG");
+        assert_eq!(
+            message.parts[children[0]].content_type().unwrap().subtype(),
+            Some("html")
+        );
+        assert_eq!(message.attachment_count(), 2);
+    }
+
+    #[test]
+    fn build_into_mime_part() {
+        let mut root = MessageBuilder::new()
+            .text_body("Hello")
+            .html_body("<p>Hello</p>")
+            .attachment("text/plain", "a.txt", "First")
+            .into_mime_part();
+        let BodyPart::Multipart(parts) = &root.contents else {
+            panic!("expected multipart/mixed");
+        };
+        assert_eq!(parts.len(), 2);
+        assert!(matches!(parts[0].contents, BodyPart::Multipart(_)));
+
+        root.add_part(MimePart::new("text/plain", "Second").attachment("b.txt"));
+        let output = MessageBuilder::new()
+            .from("john@doe.com")
+            .body(root)
+            .write_to_vec()
+            .unwrap();
+        let message = MessageParser::new().parse(&output).unwrap();
+        assert_eq!(message.body_text(0).unwrap(), "Hello");
+        assert_eq!(message.body_html(0).unwrap(), "<p>Hello</p>");
+        assert_eq!(
+            message
+                .attachments()
+                .map(|part| part.attachment_name().unwrap())
+                .collect::<Vec<_>>(),
+            ["a.txt", "b.txt"]
+        );
+
+        // Single part messages are returned as is
+        let root = MessageBuilder::new().text_body("Hello").into_mime_part();
+        assert!(matches!(root.contents, BodyPart::Text(_)));
+    }
+
+    #[test]
+    fn build_content_length() {
+        for (line_ending, eol) in [(LineEnding::CrLf, "\r\n"), (LineEnding::Lf, "\n")] {
+            let message = MessageBuilder::new()
+                .body(MimePart::new(
+                    "multipart/mixed",
+                    vec![
+                        MimePart::new("text/plain", "¡Hola, mundo!\n".repeat(10))
+                            .with_content_length(),
+                        MimePart::new("application/octet-stream", vec![0xffu8; 100])
+                            .attachment("data.bin")
+                            .with_content_length(),
+                        MimePart::new("text/plain", "No length"),
+                    ],
+                ))
+                .line_ending(line_ending)
+                .write_to_string()
+                .unwrap();
+
+            let delimiter = format!("{eol}--");
+            let lengths = message
+                .split("Content-Length: ")
+                .skip(1)
+                .map(|part| {
+                    let (len, body) = part.split_once(&format!("{eol}{eol}")).unwrap();
+                    let body_len = body.find(&delimiter).unwrap();
+                    (len.parse::<usize>().unwrap(), body_len)
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(lengths.len(), 2, "{message}");
+            for (len, body_len) in lengths {
+                assert_eq!(len, body_len, "{message}");
+            }
+
+            let message = MessageParser::new().parse(&message).unwrap();
+            assert_eq!(
+                message.body_text(0).unwrap().replace('\r', ""),
+                "¡Hola, mundo!\n".repeat(10)
+            );
+            assert_eq!(
+                message.attachment(0).unwrap().contents(),
+                &[0xffu8; 100][..]
+            );
+        }
+    }
+
+    #[test]
+    fn build_from_raw_part() {
+        let raw_part = concat!(
+            "Content-Type: application/json\r\n",
+            "Content-Transfer-Encoding: base64\r\n",
+            "Content-Disposition: attachment; filename=\"data.json\"\r\n",
+            "\r\n",
+            "eyJhIjoxfQ=="
+        );
+        let message = MessageBuilder::new()
+            .body(MimePart::new(
+                "multipart/mixed",
+                vec![
+                    MimePart::new("text/plain", "See attached."),
+                    MimePart::from_raw_part(raw_part.as_bytes()),
+                ],
+            ))
+            .write_to_string()
+            .unwrap();
+        assert!(message.contains(raw_part), "{message}");
+
+        let message = MessageParser::new().parse(&message).unwrap();
+        assert_eq!(message.body_text(0).unwrap(), "See attached.");
+        let attachment = message.attachment(0).unwrap();
+        assert_eq!(attachment.attachment_name(), Some("data.json"));
+        assert_eq!(attachment.contents(), b"{\"a\":1}");
+    }
+
+    #[test]
+    fn build_line_ending() {
+        let text = "Héllo, wörld! ".repeat(20);
+        let build = |line_ending| {
+            MessageBuilder::new()
+                .from("jane@example.com")
+                .subject("Line endings")
+                .message_id("line-ending@example.com")
+                .date(Date::new(0))
+                .text_body(text.as_str())
+                .attachment("application/octet-stream", "data.bin", vec![0u8; 200])
+                .write_options(
+                    WriteOptions::new()
+                        .line_ending(line_ending)
+                        .trailing_crlf(true),
+                )
+        };
+
+        let mut output = Vec::new();
+        let stats = build(LineEnding::Lf)
+            .write_to_with_stats(&mut output)
+            .unwrap();
+        assert_eq!(stats.total_bytes, output.len());
+        assert!(!output.contains(&b'\r'));
+        assert!(output.ends_with(b"\n") && !output.ends_with(b"\n\n"));
+
+        // Apart from the boundaries, the output matches the CRLF message
+        let crlf = build(LineEnding::CrLf).write_to_string().unwrap();
+        let lf = String::from_utf8(output).unwrap();
+        assert_eq!(lf.lines().count(), crlf.lines().count());
+        for (lf, crlf) in lf.lines().zip(crlf.lines()) {
+            if !lf.contains("boundary") && !lf.starts_with("--") {
+                assert_eq!(lf, crlf);
+            }
+        }
+
+        let message = MessageParser::new().parse(lf.as_bytes()).unwrap();
+        assert_eq!(message.body_text(0).unwrap(), text);
+        assert_eq!(message.attachment(0).unwrap().contents(), &[0u8; 200][..]);
+
+        let message = MessageBuilder::new()
+            .text_body("Hello")
+            .line_ending(LineEnding::Lf)
+            .write_to_string()
+            .unwrap();
+        assert!(!message.contains('\r'), "{message}");
+
+        // Pass-through bodies are written unchanged
+        let mut output = Vec::new();
+        MessageBuilder::new()
+            .body(MimePart::new(
+                "multipart/mixed",
+                vec![
+                    MimePart::new("text/plain", "Hello\r\nworld"),
+                    MimePart::new_binary_encoded(
+                        "application/octet-stream",
+                        "binary",
+                        &b"\x00\r\n\x01"[..],
+                    )
+                    .with_content_length(),
+                    MimePart::from_raw_part(&b"Content-Type: text/plain\r\n\r\nraw\r\n\r"[..]),
+                    MimePart::new("application/x-custom", &b"data"[..]).custom_encoding(
+                        "x-custom",
+                        |input, output| {
+                            output.write_all(b"\x02\r\n")?;
+                            output.write_all(input)?;
+                            Ok(input.len() + 3)
+                        },
+                    ),
+                ],
+            ))
+            .line_ending(LineEnding::Lf)
+            .write_to(&mut output)
+            .unwrap();
+        let message = String::from_utf8(output).unwrap();
+        assert!(message.contains("Hello\nworld\n"), "{message:?}");
+        assert!(
+            message.contains("Content-Length: 4\n\n\x00\r\n\x01\n--"),
+            "{message:?}"
+        );
+        assert!(
+            message.contains("\nContent-Type: text/plain\n\nraw\r\n\r\n--"),
+            "{message:?}"
+        );
+        assert!(message.contains("\n\n\x02\r\ndata\n--"), "{message:?}");
+    }
+
+    #[test]
+    fn build_media_types() {
+        let message = MessageBuilder::new()
+            .body(MimePart::new(
+                MediaType::MultipartMixed,
+                vec![
+                    MimePart::new(
+                        ContentType::new(MediaType::MultipartAlternative),
+                        vec![
+                            MimePart::new(MediaType::TextPlain, "Hello"),
+                            MimePart::new(MediaType::TextHtml, "<p>Hello</p>"),
+                        ],
+                    ),
+                    MimePart::new("application/x-custom", &b"data"[..]),
+                ],
+            ))
+            .write_to_string()
+            .unwrap();
+        let message = MessageParser::new().parse(&message).unwrap();
+        assert_eq!(message.body_text(0).unwrap(), "Hello");
+        assert_eq!(message.body_html(0).unwrap(), "<p>Hello</p>");
+        assert_eq!(
+            message
+                .attachment(0)
+                .unwrap()
+                .content_type()
+                .unwrap()
+                .subtype(),
+            Some("x-custom")
+        );
+
+        let message = MessageBuilder::new()
+            .text_body("Hello")
+            .attachment(MediaType::ApplicationOctetStream, "data.bin", &b"bin"[..])
+            .write_to_string()
+            .unwrap();
+        assert!(
+            message.contains("Content-Type: application/octet-stream\r\n"),
+            "{message}"
+        );
+        assert_eq!(MediaType::MessageRfc822.to_string(), "message/rfc822");
+    }
+
+    #[test]
+    fn build_header_injection() {
+        let injected = "a\r\nBcc: evil@x";
+        let message = MessageBuilder::new()
+            .from("jane@example.com")
+            .subject(injected)
+            .text_body("Hello")
+            .write_to_string()
+            .unwrap();
+        assert!(
+            !message.split("\r\n").any(|line| line.starts_with("Bcc:")),
+            "{message}"
+        );
+        let parsed = MessageParser::new().parse(&message).unwrap();
+        assert_eq!(parsed.subject(), Some(injected));
+        assert!(parsed.bcc().is_none());
+
+        // Fallible setters reject or strip line breaks
+        assert_eq!(
+            MessageBuilder::new()
+                .try_subject(injected)
+                .unwrap_err()
+                .kind(),
+            crate::io::ErrorKind::InvalidInput
+        );
+        assert!(MessageBuilder::new()
+            .try_header(
+                "To",
+                Address::new_address(Some("Jane\n"), "jane@example.com")
+            )
+            .is_err());
+        assert!(MessageBuilder::new()
+            .try_header("X-Bad:", Text::new("value"))
+            .is_err());
+        let message = MessageBuilder::new()
+            .newline_policy(NewlinePolicy::Strip)
+            .try_subject(injected)
+            .unwrap()
+            .try_header("X-Custom", Raw::new("b\nc"))
+            .unwrap()
+            .text_body("Hello")
+            .write_to_string()
+            .unwrap();
+        assert!(message.contains("Subject: aBcc: evil@x\r\n"), "{message}");
+        assert!(message.contains("X-Custom: bc\r\n"), "{message}");
+
+        // Writers refuse values that would break out of the header
+        for value in [
+            HeaderType::from(Raw::new("a\r\nBcc: evil@x")),
+            Address::new_address(None::<&str>, "jane@example.com>\r\nBcc: <evil@x").into(),
+            ContentType::new("text/plain\r\nBcc: evil@x.com").into(),
+            ContentType::new("text/plain")
+                .attribute("x\r\nBcc: evil@x.com", "1")
+                .into(),
+        ] {
+            assert!(MessageBuilder::new()
+                .header("X-Injected", value)
+                .write_to_string()
+                .is_err());
+        }
+    }
+
+    #[test]
+    fn build_smime() {
+        let mut signed_content = Vec::new();
+        let message = MessageBuilder::new()
+            .from("jane@example.com")
+            .subject("Signed")
+            .text_body("Hello, world!")
+            .attachment("application/octet-stream", "data.bin", &b"\x00\x01\x02"[..])
+            .smime_sign(|content| {
+                signed_content = content.to_vec();
+                Ok(b"signature".to_vec())
+            })
+            .unwrap()
+            .write_to_vec()
+            .unwrap();
+
+        let parsed = MessageParser::new().parse(&message).unwrap();
+        let content_type = parsed.content_type().unwrap();
+        assert_eq!(content_type.subtype(), Some("signed"));
+        assert_eq!(
+            content_type.attribute("protocol"),
+            Some("application/pkcs7-signature")
+        );
+        assert_eq!(content_type.attribute("micalg"), Some("sha-256"));
+        assert_eq!(parsed.body_text(0).unwrap(), "Hello, world!");
+        let signature = parsed.attachment(1).unwrap();
+        assert_eq!(signature.attachment_name(), Some("smime.p7s"));
+        assert_eq!(signature.contents(), b"signature");
+
+        // The signed bytes are transmitted unchanged
+        let boundary = content_type.attribute("boundary").unwrap();
+        let message = String::from_utf8(message.clone()).unwrap();
+        let start = message.find(&format!("--{boundary}\r\n")).unwrap() + boundary.len() + 4;
+        let end = message.rfind(&format!("\r\n--{boundary}\r\n")).unwrap();
+        assert!(signed_content.starts_with(b"Content-Type: multipart/mixed;"));
+        assert_eq!(&message.as_bytes()[start..end], signed_content);
+
+        let mut encrypted_content = Vec::new();
+        let message = MessageBuilder::new()
+            .from("jane@example.com")
+            .text_body("Secret")
+            .smime_sign(|_| Ok(b"signature".to_vec()))
+            .unwrap()
+            .smime_encrypt(|content| {
+                encrypted_content = content.to_vec();
+                Ok(content.iter().rev().copied().collect())
+            })
+            .unwrap()
+            .write_to_vec()
+            .unwrap();
+        assert!(encrypted_content.starts_with(b"Content-Type: multipart/signed;"));
+
+        let parsed = MessageParser::new().parse(&message).unwrap();
+        let content_type = parsed.content_type().unwrap();
+        assert_eq!(content_type.ctype(), "application");
+        assert_eq!(content_type.subtype(), Some("pkcs7-mime"));
+        assert_eq!(content_type.attribute("smime-type"), Some("enveloped-data"));
+        let contents = parsed.attachment(0).unwrap().contents().to_vec();
+        assert_eq!(
+            contents.into_iter().rev().collect::<Vec<_>>(),
+            encrypted_content
+        );
+    }
 }
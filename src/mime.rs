@@ -9,25 +9,32 @@
  * except according to those terms.
  */
 
-use std::{
+use alloc::{
     borrow::Cow,
-    cell::Cell,
-    collections::hash_map::DefaultHasher,
-    hash::{Hash, Hasher},
-    io::{self, Write},
-    thread,
-    time::{Duration, SystemTime, UNIX_EPOCH},
+    format,
+    string::{String, ToString},
+    sync::Arc,
+    vec,
+    vec::Vec,
 };
 
 use crate::{
     encoders::{
-        base64::base64_encode_mime,
-        encode::{get_encoding_type, EncodingType},
-        quoted_printable::quoted_printable_encode,
+        base64::{base64_encode_mime, base64_encoded_len},
+        counting::CountingWriter,
+        encode::{
+            get_encoding_type, get_encoding_type_and_len, normalize_line_endings, EncodingType,
+        },
+        line_ending::LineEndingWriter,
+        quoted_printable::{quoted_printable_encode, QpMode},
     },
     headers::{
-        content_type::ContentType, message_id::MessageId, raw::Raw, text::Text, Header, HeaderType,
+        content_type::ContentType, date::Date, message_id::MessageId, raw::Raw, text::Text,
+        write_header_line, Header, HeaderType,
     },
+    io::{self, Write},
+    sha256::Sha256,
+    LineEnding, WriteOptions,
 };
 
 /// MIME part of an e-mail.
@@ -35,6 +42,56 @@ use crate::{
 pub struct MimePart<'x> {
     pub headers: Vec<(Cow<'x, str>, HeaderType<'x>)>,
     pub contents: BodyPart<'x>,
+    pub prefer_quoted_printable: bool,
+    pub encoding: Option<EncodingType>,
+    pub custom_encoding: Option<CustomEncoding<'x>>,
+    pub content_length: bool,
+}
+
+/// Optional Content-Disposition parameters of an attachment (RFC 2183).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AttachmentMeta {
+    /// Size of the attachment in bytes, before encoding.
+    pub size: Option<usize>,
+    pub created: Option<Date>,
+    pub modified: Option<Date>,
+}
+
+type EncoderFn<'x> = Arc<dyn Fn(&[u8], &mut dyn Write) -> io::Result<usize> + Send + Sync + 'x>;
+
+/// Content-Transfer-Encoding implemented by the caller, such as a
+/// compression scheme the crate does not know about.
+#[derive(Clone)]
+pub struct CustomEncoding<'x> {
+    pub name: Cow<'x, str>,
+    encoder: EncoderFn<'x>,
+}
+
+impl<'x> CustomEncoding<'x> {
+    /// Create a new custom encoding named `name` that encodes the contents
+    /// using `encoder`.
+    pub fn new(
+        name: impl Into<Cow<'x, str>>,
+        encoder: impl Fn(&[u8], &mut dyn Write) -> io::Result<usize> + Send + Sync + 'x,
+    ) -> Self {
+        CustomEncoding {
+            name: name.into(),
+            encoder: Arc::new(encoder),
+        }
+    }
+
+    /// Encode `input` into `output`.
+    pub fn encode(&self, input: &[u8], output: &mut dyn Write) -> io::Result<usize> {
+        (self.encoder)(input, output)
+    }
+}
+
+impl core::fmt::Debug for CustomEncoding<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("CustomEncoding")
+            .field("name", &self.name)
+            .finish_non_exhaustive()
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -42,6 +99,80 @@ pub enum BodyPart<'x> {
     Text(Cow<'x, str>),
     Binary(Cow<'x, [u8]>),
     Multipart(Vec<MimePart<'x>>),
+    #[cfg(feature = "std")]
+    Lazy(LazyBody<'x>),
+}
+
+#[cfg(feature = "std")]
+type LazyFn<'x> = Box<dyn FnOnce() -> io::Result<Vec<u8>> + Send + 'x>;
+
+#[cfg(feature = "std")]
+enum LazyState<'x> {
+    Pending(LazyFn<'x>),
+    Ready(Vec<u8>),
+    Failed,
+}
+
+/// Binary contents generated on demand when the part is written.
+/// Clones share the generated contents, so the generator runs at most once.
+#[cfg(feature = "std")]
+#[derive(Clone)]
+pub struct LazyBody<'x> {
+    state: std::sync::Arc<std::sync::Mutex<LazyState<'x>>>,
+}
+
+#[cfg(feature = "std")]
+impl<'x> LazyBody<'x> {
+    /// Create a new lazy body from a generator function.
+    pub fn new(f: impl FnOnce() -> io::Result<Vec<u8>> + Send + 'x) -> Self {
+        LazyBody {
+            state: std::sync::Arc::new(std::sync::Mutex::new(LazyState::Pending(Box::new(f)))),
+        }
+    }
+
+    /// Returns the contents, running the generator if it has not run yet.
+    pub fn contents(&self) -> io::Result<Vec<u8>> {
+        let mut state = self
+            .state
+            .lock()
+            .map_err(|_| io::Error::other("lazy body lock poisoned"))?;
+        let contents = match core::mem::replace(&mut *state, LazyState::Failed) {
+            LazyState::Pending(f) => f()?,
+            LazyState::Ready(contents) => contents,
+            LazyState::Failed => return Err(io::Error::other("lazy body generation failed")),
+        };
+        *state = LazyState::Ready(contents.clone());
+        Ok(contents)
+    }
+
+    /// Returns the contents, avoiding a copy when they are not shared.
+    pub fn into_contents(self) -> io::Result<Vec<u8>> {
+        match std::sync::Arc::try_unwrap(self.state) {
+            Ok(state) => match state
+                .into_inner()
+                .map_err(|_| io::Error::other("lazy body lock poisoned"))?
+            {
+                LazyState::Pending(f) => f(),
+                LazyState::Ready(contents) => Ok(contents),
+                LazyState::Failed => Err(io::Error::other("lazy body generation failed")),
+            },
+            Err(state) => LazyBody { state }.contents(),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl core::fmt::Debug for LazyBody<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("LazyBody").finish_non_exhaustive()
+    }
+}
+
+#[cfg(feature = "std")]
+impl<'x> From<LazyBody<'x>> for BodyPart<'x> {
+    fn from(value: LazyBody<'x>) -> Self {
+        BodyPart::Lazy(value)
+    }
 }
 
 impl<'x> From<&'x str> for BodyPart<'x> {
@@ -74,6 +205,12 @@ impl<'x> From<Cow<'x, str>> for BodyPart<'x> {
     }
 }
 
+impl<'x> From<Cow<'x, [u8]>> for BodyPart<'x> {
+    fn from(value: Cow<'x, [u8]>) -> Self {
+        BodyPart::Binary(value)
+    }
+}
+
 impl<'x> From<Vec<u8>> for BodyPart<'x> {
     fn from(value: Vec<u8>) -> Self {
         BodyPart::Binary(value.into())
@@ -86,6 +223,64 @@ impl<'x> From<Vec<MimePart<'x>>> for BodyPart<'x> {
     }
 }
 
+impl<'x> FromIterator<MimePart<'x>> for BodyPart<'x> {
+    fn from_iter<T: IntoIterator<Item = MimePart<'x>>>(iter: T) -> Self {
+        BodyPart::Multipart(iter.into_iter().collect())
+    }
+}
+
+/// Common media types, which can be used wherever a content type is
+/// expected. Other media types can still be given as strings.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum MediaType {
+    TextPlain,
+    TextHtml,
+    MultipartMixed,
+    MultipartAlternative,
+    MultipartRelated,
+    MultipartReport,
+    MultipartSigned,
+    MultipartEncrypted,
+    ApplicationOctetStream,
+    MessageRfc822,
+}
+
+impl MediaType {
+    /// Returns the media type as a `type/subtype` string.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MediaType::TextPlain => "text/plain",
+            MediaType::TextHtml => "text/html",
+            MediaType::MultipartMixed => "multipart/mixed",
+            MediaType::MultipartAlternative => "multipart/alternative",
+            MediaType::MultipartRelated => "multipart/related",
+            MediaType::MultipartReport => "multipart/report",
+            MediaType::MultipartSigned => "multipart/signed",
+            MediaType::MultipartEncrypted => "multipart/encrypted",
+            MediaType::ApplicationOctetStream => "application/octet-stream",
+            MediaType::MessageRfc822 => "message/rfc822",
+        }
+    }
+}
+
+impl core::fmt::Display for MediaType {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl<'x> From<MediaType> for Cow<'x, str> {
+    fn from(value: MediaType) -> Self {
+        Cow::Borrowed(value.as_str())
+    }
+}
+
+impl<'x> From<MediaType> for ContentType<'x> {
+    fn from(value: MediaType) -> Self {
+        ContentType::new(value)
+    }
+}
+
 impl<'x> From<&'x str> for ContentType<'x> {
     fn from(value: &'x str) -> Self {
         ContentType::new(value)
@@ -104,9 +299,77 @@ impl<'x> From<&'x String> for ContentType<'x> {
     }
 }
 
-thread_local!(static COUNTER: Cell<u64> = const { Cell::new(0) });
+// Upper bounds used when estimating sizes without serializing
+pub(crate) const ESTIMATED_BOUNDARY_LEN: usize = 64;
+pub(crate) const ESTIMATED_BOUNDARY_ATTR_LEN: usize = ESTIMATED_BOUNDARY_LEN + 16;
+pub(crate) const ESTIMATED_CONTAINER_LEN: usize =
+    40 + ESTIMATED_BOUNDARY_ATTR_LEN + ESTIMATED_BOUNDARY_LEN;
+const ESTIMATED_CTE_LEN: usize = 48;
+const ESTIMATED_CONTENT_LENGTH_LEN: usize = 40;
 
+pub(crate) fn estimated_header_size(
+    header_name: &str,
+    header_value: &HeaderType,
+    buf: &mut Vec<u8>,
+) -> usize {
+    buf.clear();
+    let _ = header_value.write_header(&mut *buf, header_name.len() + 2);
+    header_name.len() + 2 + buf.len()
+}
+
+/// 64-bit FNV-1a hash, stable across platforms and releases.
+pub(crate) fn content_hash(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |hash, &ch| {
+        (hash ^ ch as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Writer computing the SHA-256 digest of everything written to it.
+pub(crate) struct ContentHasher {
+    hasher: Sha256,
+}
+
+impl ContentHasher {
+    pub(crate) fn new() -> Self {
+        ContentHasher {
+            hasher: Sha256::new(),
+        }
+    }
+
+    pub(crate) fn update(&mut self, data: &[u8]) {
+        self.hasher.update(data);
+    }
+
+    /// Returns the first 128 bits of the digest.
+    pub(crate) fn finish(self) -> u128 {
+        let digest = self.hasher.finish();
+        u128::from_be_bytes(digest[..16].try_into().unwrap())
+    }
+}
+
+impl Write for ContentHasher {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(feature = "std")]
+thread_local!(static COUNTER: std::cell::Cell<u64> = const { std::cell::Cell::new(0) });
+
+#[cfg(feature = "std")]
 pub fn make_boundary(separator: &str) -> String {
+    use std::{
+        collections::hash_map::DefaultHasher,
+        hash::{Hash, Hasher},
+        thread,
+        time::{Duration, SystemTime, UNIX_EPOCH},
+    };
+
     // Create a pseudo-unique boundary
     let mut s = DefaultHasher::new();
     ((&s as *const DefaultHasher) as usize).hash(&mut s);
@@ -129,6 +392,53 @@ pub fn make_boundary(separator: &str) -> String {
     )
 }
 
+#[cfg(not(feature = "std"))]
+static ENTROPY_SEED: core::sync::atomic::AtomicU64 = core::sync::atomic::AtomicU64::new(0);
+// Unit tests build messages without setting a seed
+#[cfg(not(feature = "std"))]
+static ENTROPY_SEEDED: core::sync::atomic::AtomicBool =
+    core::sync::atomic::AtomicBool::new(cfg!(test));
+
+/// Set the random seed used to generate boundaries, Message-IDs and
+/// Content-IDs. Without `std` there is no clock or other source of
+/// entropy, so this must be called once with a value obtained from a
+/// random source (such as a hardware RNG) before building any message.
+#[cfg(not(feature = "std"))]
+pub fn set_entropy_seed(seed: u64) {
+    use core::sync::atomic::Ordering;
+
+    ENTROPY_SEED.store(seed, Ordering::Relaxed);
+    ENTROPY_SEEDED.store(true, Ordering::Release);
+}
+
+/// Returns a unique string derived from the seed set with
+/// `set_entropy_seed`.
+///
+/// # Panics
+///
+/// Panics if `set_entropy_seed` has not been called.
+#[cfg(not(feature = "std"))]
+pub fn make_boundary(separator: &str) -> String {
+    use core::sync::atomic::{AtomicU64, Ordering};
+
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    assert!(
+        ENTROPY_SEEDED.load(Ordering::Acquire),
+        "set_entropy_seed must be called before building messages without std"
+    );
+    let seed = ENTROPY_SEED.load(Ordering::Relaxed);
+    let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+
+    // SplitMix64 over the seeded counter
+    let mut z = seed.wrapping_add(count.wrapping_add(1).wrapping_mul(0x9e3779b97f4a7c15));
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^= z >> 31;
+
+    format!("{:x}{}{:x}", z, separator, count)
+}
+
 impl<'x> MimePart<'x> {
     /// Create a new MIME part.
     pub fn new(
@@ -147,17 +457,65 @@ impl<'x> MimePart<'x> {
         Self {
             contents,
             headers: vec![("Content-Type".into(), content_type.into())],
+            prefer_quoted_printable: false,
+            encoding: None,
+            custom_encoding: None,
+            content_length: false,
         }
     }
 
+    /// Create a new multipart/* MIME part from an iterator of parts.
+    pub fn new_multipart_from_iter(
+        content_type: impl Into<ContentType<'x>>,
+        parts: impl IntoIterator<Item = MimePart<'x>>,
+    ) -> Self {
+        Self::new(content_type, parts.into_iter().collect::<BodyPart>())
+    }
+
     /// Create a new raw MIME part that includes both headers and body.
     pub fn raw(contents: impl Into<BodyPart<'x>>) -> Self {
         Self {
             contents: contents.into(),
             headers: vec![],
+            prefer_quoted_printable: false,
+            encoding: None,
+            custom_encoding: None,
+            content_length: false,
         }
     }
 
+    /// Create a MIME part from a complete, already serialized MIME entity
+    /// (headers and body), such as a part received from another system.
+    /// The bytes are written verbatim, without adding headers or encoding,
+    /// except that the header block follows `WriteOptions::line_ending`.
+    pub fn from_raw_part(contents: impl Into<Cow<'x, [u8]>>) -> Self {
+        Self::raw(BodyPart::Binary(contents.into()))
+    }
+
+    /// Create a new MIME part from contents that are already encoded using
+    /// the specified Content-Transfer-Encoding (for example, an attachment
+    /// being forwarded). The contents are written as-is, preserving their
+    /// original line wrapping.
+    pub fn new_binary_encoded(
+        content_type: impl Into<ContentType<'x>>,
+        transfer_encoding: impl Into<Cow<'x, str>>,
+        contents: impl Into<Cow<'x, [u8]>>,
+    ) -> Self {
+        Self::new(content_type, BodyPart::Binary(contents.into()))
+            .transfer_encoding(transfer_encoding)
+    }
+
+    /// Create a new text/calendar (iCalendar) MIME part with the given
+    /// iTIP method, such as `REQUEST` or `CANCEL`.
+    pub fn new_calendar(method: impl Into<Cow<'x, str>>, ics: impl Into<Cow<'x, str>>) -> Self {
+        Self::new(
+            ContentType::new("text/calendar")
+                .attribute("method", method)
+                .attribute("charset", "utf-8"),
+            BodyPart::Text(ics.into()),
+        )
+    }
+
     /// Set the attachment filename of a MIME part.
     pub fn attachment(mut self, filename: impl Into<Cow<'x, str>>) -> Self {
         self.headers.push((
@@ -169,6 +527,30 @@ impl<'x> MimePart<'x> {
         self
     }
 
+    /// Set the attachment filename of a MIME part along with the optional
+    /// size and dates parameters of RFC 2183.
+    pub fn attachment_with(
+        mut self,
+        filename: impl Into<Cow<'x, str>>,
+        meta: AttachmentMeta,
+    ) -> Self {
+        let mut disposition = ContentType::new("attachment").attribute("filename", filename);
+        for (name, date) in [
+            ("creation-date", meta.created),
+            ("modification-date", meta.modified),
+        ] {
+            if let Some(date) = date {
+                disposition = disposition.attribute(name, date.to_rfc822());
+            }
+        }
+        if let Some(size) = meta.size {
+            disposition = disposition.attribute("size", size.to_string());
+        }
+        self.headers
+            .push(("Content-Disposition".into(), disposition.into()));
+        self
+    }
+
     /// Set the MIME part as inline.
     pub fn inline(mut self) -> Self {
         self.headers.push((
@@ -185,6 +567,55 @@ impl<'x> MimePart<'x> {
         self
     }
 
+    /// Set the Content-Description header of a MIME part.
+    pub fn description(mut self, value: impl Into<Cow<'x, str>>) -> Self {
+        self.headers
+            .push(("Content-Description".into(), Text::new(value).into()));
+        self
+    }
+
+    /// Set the boundary of a multipart MIME part instead of generating a
+    /// random one. Returns an error if the boundary is not 1 to 70 valid
+    /// characters (RFC 2046) or the part has no structured Content-Type.
+    pub fn boundary(mut self, value: impl Into<Cow<'x, str>>) -> io::Result<Self> {
+        let value = value.into();
+        if value.is_empty()
+            || value.len() > 70
+            || value.ends_with(' ')
+            || !value
+                .bytes()
+                .all(|ch| ch.is_ascii_alphanumeric() || b"'()+_,-./:=? ".contains(&ch))
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid boundary {value:?}"),
+            ));
+        }
+
+        match self
+            .headers
+            .iter_mut()
+            .find(|(name, _)| name.eq_ignore_ascii_case("Content-Type"))
+        {
+            Some((_, HeaderType::ContentType(ct))) => {
+                if let Some((_, boundary)) = ct
+                    .attributes
+                    .iter_mut()
+                    .find(|(name, _)| name.eq_ignore_ascii_case("boundary"))
+                {
+                    *boundary = value;
+                } else {
+                    ct.attributes.push(("boundary".into(), value));
+                }
+                Ok(self)
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "part has no structured Content-Type",
+            )),
+        }
+    }
+
     /// Set the Content-ID header of a MIME part.
     pub fn cid(mut self, value: impl Into<Cow<'x, str>>) -> Self {
         self.headers
@@ -199,7 +630,45 @@ impl<'x> MimePart<'x> {
         self
     }
 
+    /// Encode text/* contents as quoted-printable even when base64 would be
+    /// shorter, keeping the body partially readable in transit. Binary
+    /// parts are still encoded as base64.
+    pub fn prefer_quoted_printable(mut self) -> Self {
+        self.prefer_quoted_printable = true;
+        self
+    }
+
+    /// Add a Content-Length header with the number of body octets written
+    /// after encoding. The encoded body is buffered in memory to compute it.
+    /// Has no effect on multipart and raw parts without headers.
+    pub fn with_content_length(mut self) -> Self {
+        self.content_length = true;
+        self
+    }
+
+    /// Encode the contents using the specified encoding instead of detecting
+    /// the most suitable one. With `EncodingType::None`, writing fails with
+    /// an `InvalidData` error unless the contents are valid 7bit data.
+    pub fn force_encoding(mut self, encoding: EncodingType) -> Self {
+        self.encoding = Some(encoding);
+        self
+    }
+
+    /// Encode the contents using a caller-supplied encoder, written with
+    /// `Content-Transfer-Encoding: {name}`, instead of Base64 or
+    /// quoted-printable.
+    pub fn custom_encoding(
+        mut self,
+        name: impl Into<Cow<'x, str>>,
+        encoder: impl Fn(&[u8], &mut dyn Write) -> io::Result<usize> + Send + Sync + 'x,
+    ) -> Self {
+        self.custom_encoding = Some(CustomEncoding::new(name, encoder));
+        self
+    }
+
     /// Disable automatic Content-Transfer-Encoding detection and treat this as a raw MIME part
+    /// whose contents are already encoded. Use `force_encoding` to have the contents encoded
+    /// with a specific encoding instead.
     pub fn transfer_encoding(mut self, value: impl Into<Cow<'x, str>>) -> Self {
         self.headers
             .push(("Content-Transfer-Encoding".into(), Raw::new(value).into()));
@@ -222,9 +691,148 @@ impl<'x> MimePart<'x> {
             BodyPart::Text(b) => b.len(),
             BodyPart::Binary(b) => b.len(),
             BodyPart::Multipart(bl) => bl.iter().map(|b| b.size()).sum(),
+            #[cfg(feature = "std")]
+            BodyPart::Lazy(_) => 0,
+        }
+    }
+
+    /// Returns an estimate of the part's size once encoded, including its
+    /// headers and any multipart boundaries. The estimate is never smaller
+    /// than the actual size by more than a few bytes per part, except for
+    /// lazy bodies whose contents are not known until written and custom
+    /// encodings, which are assumed not to grow the contents.
+    pub fn estimated_size(&self) -> usize {
+        let mut is_text = false;
+        let mut is_attachment = false;
+        let mut is_raw = self.headers.is_empty();
+        let mut buf = Vec::new();
+        let mut size = 0;
+
+        for (header_name, header_value) in &self.headers {
+            if header_name.eq_ignore_ascii_case("Content-Type") {
+                is_text = header_value
+                    .as_content_type()
+                    .map(|v| v.is_text())
+                    .unwrap_or(false);
+            } else if header_name.eq_ignore_ascii_case("Content-Disposition") {
+                is_attachment = header_value
+                    .as_content_type()
+                    .map(|v| v.is_attachment())
+                    .unwrap_or(false);
+            } else if header_name.eq_ignore_ascii_case("Content-Transfer-Encoding") {
+                is_raw = true;
+            }
+            size += estimated_header_size(header_name, header_value, &mut buf);
+        }
+        if self.content_length && !self.headers.is_empty() {
+            size += ESTIMATED_CONTENT_LENGTH_LEN;
+        }
+
+        // Worst case, every byte is escaped and lines are soft broken
+        let max_qp_len = |len: usize| 3 * len + 3 * (3 * len / 73 + 1);
+        let encoded_text_len = |input: &[u8]| match self.encoding {
+            Some(EncodingType::Base64) => base64_encoded_len(input.len(), false),
+            Some(EncodingType::QuotedPrintable(_)) => max_qp_len(input.len()),
+            Some(EncodingType::None) => 2 * input.len(),
+            None => match get_encoding_type_and_len(input, false, !is_attachment) {
+                (EncodingType::Base64, _) if self.prefer_quoted_printable => {
+                    max_qp_len(input.len())
+                }
+                (_, len) => len,
+            },
+        };
+
+        size + match &self.contents {
+            BodyPart::Text(_) | BodyPart::Binary(_) if is_raw => 2 + self.size(),
+            BodyPart::Text(_) | BodyPart::Binary(_) if self.custom_encoding.is_some() => {
+                ESTIMATED_CTE_LEN + self.size()
+            }
+            BodyPart::Text(text) => ESTIMATED_CTE_LEN + encoded_text_len(text.as_bytes()),
+            BodyPart::Binary(binary) if is_text || self.encoding.is_some() => {
+                ESTIMATED_CTE_LEN + encoded_text_len(binary.as_ref())
+            }
+            BodyPart::Binary(binary) => ESTIMATED_CTE_LEN + base64_encoded_len(binary.len(), false),
+            #[cfg(feature = "std")]
+            BodyPart::Lazy(_) => ESTIMATED_CTE_LEN,
+            BodyPart::Multipart(parts) => {
+                ESTIMATED_BOUNDARY_ATTR_LEN
+                    + 2
+                    + parts
+                        .iter()
+                        .map(|part| ESTIMATED_BOUNDARY_LEN + part.estimated_size())
+                        .sum::<usize>()
+                    + ESTIMATED_BOUNDARY_LEN
+                    + 2
+            }
+        }
+    }
+
+    /// Feed the part's headers and contents to a hasher.
+    pub(crate) fn hash_contents(&self, hasher: &mut ContentHasher) -> io::Result<()> {
+        for (header_name, header_value) in &self.headers {
+            hasher.update(header_name.as_bytes());
+            hasher.update(b": ");
+            header_value.write_header(&mut *hasher, header_name.len() + 2)?;
+        }
+        hasher.update(b"\r\n");
+        match &self.contents {
+            BodyPart::Text(text) => hasher.update(text.as_bytes()),
+            BodyPart::Binary(binary) => hasher.update(binary.as_ref()),
+            BodyPart::Multipart(parts) => {
+                for part in parts {
+                    hasher.update(b"\r\n--\r\n");
+                    part.hash_contents(hasher)?;
+                }
+            }
+            #[cfg(feature = "std")]
+            BodyPart::Lazy(lazy) => hasher.update(&lazy.contents()?),
+        }
+        hasher.update(b"\r\n--\r\n");
+        Ok(())
+    }
+
+    /// Returns the contents of a text or binary part.
+    pub(crate) fn leaf_contents(&self) -> Option<&[u8]> {
+        match &self.contents {
+            BodyPart::Text(text) => Some(text.as_bytes()),
+            BodyPart::Binary(binary) => Some(binary.as_ref()),
+            _ => None,
+        }
+    }
+
+    /// Add a `charset=utf-8` parameter to text/* parts without a charset
+    /// whose contents are valid UTF-8.
+    pub(crate) fn add_text_charset(&mut self) {
+        let is_utf8 = match &self.contents {
+            BodyPart::Text(_) => true,
+            BodyPart::Binary(binary) => core::str::from_utf8(binary).is_ok(),
+            _ => false,
+        };
+        if let Some((_, HeaderType::ContentType(ct))) = self
+            .headers
+            .iter_mut()
+            .find(|(name, _)| name.eq_ignore_ascii_case("Content-Type"))
+        {
+            if is_utf8
+                && ct.is_text()
+                && !ct
+                    .attributes
+                    .iter()
+                    .any(|(name, _)| name.eq_ignore_ascii_case("charset"))
+            {
+                ct.attributes.push(("charset".into(), "utf-8".into()));
+            }
         }
     }
 
+    /// Returns the Content-Type header value.
+    pub(crate) fn content_type(&self) -> Option<&HeaderType<'x>> {
+        self.headers
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case("Content-Type"))
+            .map(|(_, value)| value)
+    }
+
     /// Add a body part to a multipart/* MIME part.
     pub fn add_part(&mut self, part: MimePart<'x>) {
         if let BodyPart::Multipart(ref mut parts) = self.contents {
@@ -232,54 +840,51 @@ impl<'x> MimePart<'x> {
         }
     }
 
-    /// Write the MIME part to a writer.
-    pub fn write_part(self, mut output: impl Write) -> io::Result<usize> {
+    /// Write the MIME part to a writer and return the number of bytes written.
+    pub fn write_part(self, output: impl Write) -> io::Result<usize> {
+        self.write_part_with(output, &WriteOptions::default())
+    }
+
+    /// Write the MIME part using the provided options and return the number
+    /// of bytes written.
+    pub fn write_part_with(self, output: impl Write, options: &WriteOptions) -> io::Result<usize> {
+        options.validate()?;
+        let mut output = LineEndingWriter::new(CountingWriter::new(output), options.line_ending);
         let mut stack = Vec::new();
         let mut it = vec![self].into_iter();
         let mut boundary: Option<Cow<str>> = None;
 
         loop {
-            while let Some(part) = it.next() {
+            while let Some(mut part) = it.next() {
                 if let Some(boundary) = boundary.as_ref() {
                     output.write_all(b"\r\n--")?;
                     output.write_all(boundary.as_bytes())?;
                     output.write_all(b"\r\n")?;
                 }
-                match part.contents {
-                    BodyPart::Text(text) => {
-                        let mut is_attachment = false;
-                        let mut is_raw = part.headers.is_empty();
-
-                        for (header_name, header_value) in &part.headers {
-                            output.write_all(header_name.as_bytes())?;
-                            output.write_all(b": ")?;
-                            if !is_attachment && header_name == "Content-Disposition" {
-                                is_attachment = header_value
-                                    .as_content_type()
-                                    .map(|v| v.is_attachment())
-                                    .unwrap_or(false);
-                            } else if !is_raw && header_name == "Content-Transfer-Encoding" {
-                                is_raw = true;
+                let contents = match part.contents {
+                    #[cfg(feature = "std")]
+                    BodyPart::Lazy(lazy) => BodyPart::Binary(lazy.into_contents()?.into()),
+                    contents => contents,
+                };
+                if options.strict {
+                    check_7bit(&part.headers, part.encoding, &contents)?;
+                }
+                match contents {
+                    BodyPart::Text(_) | BodyPart::Binary(_) => {
+                        let (contents, mut is_text) = match contents {
+                            BodyPart::Text(Cow::Borrowed(text)) => {
+                                (Cow::Borrowed(text.as_bytes()), true)
                             }
-                            header_value.write_header(&mut output, header_name.len() + 2)?;
-                        }
-                        if !is_raw {
-                            detect_encoding(text.as_bytes(), &mut output, !is_attachment)?;
-                        } else {
-                            if !part.headers.is_empty() {
-                                output.write_all(b"\r\n")?;
+                            BodyPart::Text(Cow::Owned(text)) => {
+                                (Cow::Owned(text.into_bytes()), true)
                             }
-                            output.write_all(text.as_bytes())?;
-                        }
-                    }
-                    BodyPart::Binary(binary) => {
-                        let mut is_text = false;
+                            BodyPart::Binary(binary) => (binary, false),
+                            _ => unreachable!(),
+                        };
                         let mut is_attachment = false;
                         let mut is_raw = part.headers.is_empty();
 
                         for (header_name, header_value) in &part.headers {
-                            output.write_all(header_name.as_bytes())?;
-                            output.write_all(b": ")?;
                             if !is_text && header_name == "Content-Type" {
                                 is_text = header_value
                                     .as_content_type()
@@ -293,33 +898,161 @@ impl<'x> MimePart<'x> {
                             } else if !is_raw && header_name == "Content-Transfer-Encoding" {
                                 is_raw = true;
                             }
-                            header_value.write_header(&mut output, header_name.len() + 2)?;
                         }
 
-                        if !is_raw {
-                            if !is_text {
-                                output.write_all(b"Content-Transfer-Encoding: base64\r\n\r\n")?;
-                                base64_encode_mime(binary.as_ref(), &mut output, false)?;
-                            } else {
-                                detect_encoding(binary.as_ref(), &mut output, !is_attachment)?;
-                            }
+                        let custom_encoding = part.custom_encoding.as_ref().filter(|_| !is_raw);
+                        let mut body_encoding = None;
+                        let mut body_contents = Cow::Borrowed(contents.as_ref());
+                        let transfer_encoding = if is_raw {
+                            None
+                        } else if let Some(custom) = custom_encoding {
+                            Some(custom.name.as_ref())
                         } else {
-                            if !part.headers.is_empty() {
-                                output.write_all(b"\r\n")?;
+                            if options.ascii_charset_label && is_text && contents.is_ascii() {
+                                set_ascii_charset(&mut part.headers);
+                            }
+
+                            // Text bodies always use CRLF line endings, whatever the encoding.
+                            let is_body = is_text && !is_attachment;
+                            if is_body {
+                                body_contents = normalize_line_endings(&contents);
+                            }
+                            let encoding = part.encoding.unwrap_or_else(|| {
+                                if !is_text {
+                                    EncodingType::Base64
+                                } else {
+                                    match get_encoding_type(&body_contents, false, is_body) {
+                                        EncodingType::Base64 if part.prefer_quoted_printable => {
+                                            EncodingType::QuotedPrintable(false)
+                                        }
+                                        encoding => encoding,
+                                    }
+                                }
+                            });
+                            if part.encoding == Some(EncodingType::None) && !is_7bit(&body_contents)
+                            {
+                                return Err(io::Error::new(
+                                    io::ErrorKind::InvalidData,
+                                    "part without encoding contains data that is not 7bit",
+                                ));
+                            }
+                            body_encoding = Some((encoding, is_body));
+
+                            Some(match encoding {
+                                EncodingType::Base64 => "base64",
+                                EncodingType::QuotedPrintable(_) => "quoted-printable",
+                                EncodingType::None => "7bit",
+                            })
+                        };
+
+                        let write_body = |output: &mut dyn Write| -> io::Result<()> {
+                            match (custom_encoding, body_encoding) {
+                                (Some(custom), _) => {
+                                    custom.encode(&body_contents, output)?;
+                                }
+                                (_, Some((EncodingType::Base64, _))) => {
+                                    base64_encode_mime(&body_contents, output, false)?;
+                                }
+                                (_, Some((EncodingType::QuotedPrintable(_), is_body))) => {
+                                    let mode = if is_body {
+                                        QpMode::Body
+                                    } else {
+                                        QpMode::Attachment
+                                    };
+                                    quoted_printable_encode(&body_contents, output, mode)?;
+                                }
+                                _ => {
+                                    output.write_all(&body_contents)?;
+                                }
                             }
-                            output.write_all(binary.as_ref())?;
+                            Ok(())
+                        };
+
+                        // Pass-through bodies are written as they are, only the
+                        // bodies encoded here follow the selected line ending.
+                        let body_line_ending = if body_encoding.is_some() {
+                            options.line_ending
+                        } else {
+                            LineEnding::CrLf
+                        };
+
+                        if part.headers.is_empty() {
+                            // Only the body of a raw entity is written as is
+                            let (header, body) =
+                                body_contents.split_at(raw_header_len(&body_contents));
+                            output.write_all(header)?;
+                            output.set_line_ending(body_line_ending)?;
+                            output.write_all(body)?;
+                            output.set_line_ending(options.line_ending)?;
+                        } else if part.content_length {
+                            // The length is only known once the body is encoded
+                            let mut body = Vec::new();
+                            write_body(&mut body)?;
+                            let body_len = match body_line_ending {
+                                LineEnding::CrLf => body.len(),
+                                LineEnding::Lf => {
+                                    body.len() - body.windows(2).filter(|w| w == b"\r\n").count()
+                                }
+                            };
+                            write_part_headers(
+                                &mut output,
+                                &part.headers,
+                                transfer_encoding,
+                                options,
+                            )?;
+                            write_header_line(
+                                &mut output,
+                                "Content-Length",
+                                &Raw::new(body_len.to_string()),
+                                options,
+                            )?;
+                            output.write_all(b"\r\n")?;
+                            output.set_line_ending(body_line_ending)?;
+                            output.write_all(&body)?;
+                            output.set_line_ending(options.line_ending)?;
+                        } else {
+                            write_part_headers(
+                                &mut output,
+                                &part.headers,
+                                transfer_encoding,
+                                options,
+                            )?;
+                            output.write_all(b"\r\n")?;
+                            output.set_line_ending(body_line_ending)?;
+                            write_body(&mut output)?;
+                            output.set_line_ending(options.line_ending)?;
                         }
                     }
                     BodyPart::Multipart(parts) => {
                         if boundary.is_some() {
                             stack.push((it, boundary.take()));
                         }
+                        if stack.len() >= options.max_depth {
+                            return Err(io::Error::new(
+                                io::ErrorKind::InvalidInput,
+                                "MIME part nesting exceeds the maximum depth",
+                            ));
+                        }
 
-                        let mut found_ct = false;
-                        for (header_name, header_value) in part.headers {
-                            output.write_all(header_name.as_bytes())?;
-                            output.write_all(b": ")?;
+                        let mut headers = part.headers;
+                        headers.sort_by_key(|(header_name, _)| header_rank(header_name));
+                        if headers
+                            .first()
+                            .is_none_or(|(header_name, _)| header_rank(header_name) != 0)
+                        {
+                            let boundary_ = make_boundary("_");
+                            write_header_line(
+                                &mut output,
+                                "Content-Type",
+                                &ContentType::new("multipart/mixed")
+                                    .attribute("boundary", &boundary_),
+                                options,
+                            )?;
+                            boundary = Some(boundary_.into());
+                        }
 
+                        let mut found_ct = boundary.is_some();
+                        for (header_name, header_value) in headers {
                             if !found_ct && header_name.eq_ignore_ascii_case("Content-Type") {
                                 boundary = match header_value {
                                     HeaderType::ContentType(mut ct) => {
@@ -337,23 +1070,33 @@ impl<'x> MimePart<'x> {
                                             ));
                                             pos
                                         };
-                                        ct.write_header(&mut output, 14)?;
-                                        ct.attributes.swap_remove(bpos).1.into()
+                                        write_header_line(&mut output, &header_name, &ct, options)?;
+                                        ct.attributes.remove(bpos).1.into()
                                     }
                                     HeaderType::Raw(raw) => {
-                                        if let Some(pos) = raw.raw.find("boundary=\"") {
-                                            if let Some(boundary) = raw.raw[pos..].split('"').nth(1)
-                                            {
-                                                Some(boundary.to_string().into())
-                                            } else {
-                                                Some(make_boundary("_").into())
-                                            }
+                                        if let Some(boundary) =
+                                            raw.raw.find("boundary=\"").and_then(|pos| {
+                                                raw.raw[pos..].split('"').nth(1).map(String::from)
+                                            })
+                                        {
+                                            write_header_line(
+                                                &mut output,
+                                                &header_name,
+                                                &raw,
+                                                options,
+                                            )?;
+                                            Some(boundary.into())
                                         } else {
                                             let boundary = make_boundary("_");
-                                            output.write_all(raw.raw.as_bytes())?;
-                                            output.write_all(b"; boundary=\"")?;
-                                            output.write_all(boundary.as_bytes())?;
-                                            output.write_all(b"\"\r\n")?;
+                                            write_header_line(
+                                                &mut output,
+                                                &header_name,
+                                                &Raw::new(format!(
+                                                    "{}; boundary=\"{}\"",
+                                                    raw.raw, boundary
+                                                )),
+                                                options,
+                                            )?;
                                             Some(boundary.into())
                                         }
                                     }
@@ -361,28 +1104,29 @@ impl<'x> MimePart<'x> {
                                 };
                                 found_ct = true;
                             } else {
-                                header_value.write_header(&mut output, header_name.len() + 2)?;
+                                write_header_line(
+                                    &mut output,
+                                    &header_name,
+                                    &header_value,
+                                    options,
+                                )?;
                             }
                         }
 
-                        if !found_ct {
-                            output.write_all(b"Content-Type: ")?;
-                            let boundary_ = make_boundary("_");
-                            ContentType::new("multipart/mixed")
-                                .attribute("boundary", &boundary_)
-                                .write_header(&mut output, 14)?;
-                            boundary = Some(boundary_.into());
-                        }
-
                         output.write_all(b"\r\n")?;
                         it = parts.into_iter();
                     }
+                    #[cfg(feature = "std")]
+                    BodyPart::Lazy(_) => unreachable!(),
                 }
             }
             if let Some(boundary) = boundary {
                 output.write_all(b"\r\n--")?;
                 output.write_all(boundary.as_bytes())?;
-                output.write_all(b"--\r\n")?;
+                output.write_all(b"--")?;
+                if !stack.is_empty() || options.trailing_crlf != Some(false) {
+                    output.write_all(b"\r\n")?;
+                }
             }
             if let Some((prev_it, prev_boundary)) = stack.pop() {
                 it = prev_it;
@@ -391,35 +1135,143 @@ impl<'x> MimePart<'x> {
                 break;
             }
         }
-        Ok(0)
+        if options.trailing_crlf == Some(true)
+            && !output.get_ref().ends_with_line_ending(options.line_ending)
+        {
+            output.write_all(b"\r\n")?;
+        }
+        Ok(output.finish()?.count())
     }
 }
 
-fn detect_encoding(input: &[u8], mut output: impl Write, is_body: bool) -> io::Result<()> {
-    match get_encoding_type(input, false, is_body) {
-        EncodingType::Base64 => {
-            output.write_all(b"Content-Transfer-Encoding: base64\r\n\r\n")?;
-            base64_encode_mime(input, &mut output, false)?;
+impl<'x> Extend<MimePart<'x>> for MimePart<'x> {
+    /// Append parts to a multipart/* MIME part. Has no effect on other parts.
+    fn extend<T: IntoIterator<Item = MimePart<'x>>>(&mut self, iter: T) {
+        if let BodyPart::Multipart(ref mut parts) = self.contents {
+            parts.extend(iter);
         }
-        EncodingType::QuotedPrintable(_) => {
-            output.write_all(b"Content-Transfer-Encoding: quoted-printable\r\n\r\n")?;
-            quoted_printable_encode(input, &mut output, false, is_body)?;
+    }
+}
+
+/// Returns the length of the header block of a raw MIME entity, including
+/// the empty line that ends it, or zero when it has no empty line.
+fn raw_header_len(contents: &[u8]) -> usize {
+    if contents.starts_with(b"\r\n") {
+        2
+    } else {
+        contents
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .map_or(0, |pos| pos + 4)
+    }
+}
+
+/// Relabels a `charset=utf-8` Content-Type parameter as `us-ascii`.
+fn set_ascii_charset(headers: &mut [(Cow<str>, HeaderType)]) {
+    if let Some((_, HeaderType::ContentType(ct))) = headers
+        .iter_mut()
+        .find(|(name, _)| name.eq_ignore_ascii_case("Content-Type"))
+    {
+        if let Some((_, charset)) = ct.attributes.iter_mut().find(|(name, value)| {
+            name.eq_ignore_ascii_case("charset") && value.eq_ignore_ascii_case("utf-8")
+        }) {
+            *charset = "us-ascii".into();
         }
-        EncodingType::None => {
-            output.write_all(b"Content-Transfer-Encoding: 7bit\r\n\r\n")?;
-            if is_body {
-                let mut prev_ch = 0;
-                for ch in input {
-                    if *ch == b'\n' && prev_ch != b'\r' {
-                        output.write_all(b"\r")?;
-                    }
-                    output.write_all(&[*ch])?;
-                    prev_ch = *ch;
-                }
-            } else {
-                output.write_all(input)?;
+    }
+}
+
+/// Returns the position of a part header in the canonical header order.
+fn header_rank(name: &str) -> usize {
+    [
+        "Content-Type",
+        "Content-Transfer-Encoding",
+        "Content-Disposition",
+        "Content-ID",
+    ]
+    .iter()
+    .position(|header| header.eq_ignore_ascii_case(name))
+    .unwrap_or(4)
+}
+
+/// Writes the headers of a part in canonical order: Content-Type,
+/// Content-Transfer-Encoding, Content-Disposition, Content-ID and then
+/// all other headers in the order they were added.
+fn write_part_headers(
+    mut output: impl Write,
+    headers: &[(Cow<str>, HeaderType)],
+    transfer_encoding: Option<&str>,
+    options: &WriteOptions,
+) -> io::Result<()> {
+    for rank in 0..=4 {
+        if rank == 1 {
+            if let Some(transfer_encoding) = transfer_encoding {
+                write_header_line(
+                    &mut output,
+                    "Content-Transfer-Encoding",
+                    &Raw::new(transfer_encoding),
+                    options,
+                )?;
+            }
+        }
+        for (header_name, header_value) in headers {
+            if header_rank(header_name) == rank {
+                write_header_line(&mut output, header_name, header_value, options)?;
             }
         }
     }
     Ok(())
 }
+
+/// Returns whether the contents are 7bit data (RFC 2045, Section 2.7):
+/// ASCII without NUL, with CR and LF only as CRLF line breaks and lines of
+/// at most 998 octets.
+fn is_7bit(contents: &[u8]) -> bool {
+    let mut line_len = 0;
+    for (pos, &ch) in contents.iter().enumerate() {
+        match ch {
+            b'\r' if contents.get(pos + 1) == Some(&b'\n') => (),
+            b'\n' if pos > 0 && contents[pos - 1] == b'\r' => {
+                line_len = 0;
+                continue;
+            }
+            0 | b'\r' | b'\n' | 128.. => return false,
+            _ => (),
+        }
+        line_len += 1;
+        if line_len > 998 {
+            return false;
+        }
+    }
+    true
+}
+
+/// Fails when a part declared as 7bit contains non-ASCII bytes.
+fn check_7bit(
+    headers: &[(Cow<str>, HeaderType)],
+    encoding: Option<EncodingType>,
+    contents: &BodyPart,
+) -> io::Result<()> {
+    let contents = match contents {
+        BodyPart::Text(text) => text.as_bytes(),
+        BodyPart::Binary(binary) => binary.as_ref(),
+        _ => return Ok(()),
+    };
+    let is_7bit = match headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("Content-Transfer-Encoding"))
+    {
+        Some((_, HeaderType::Raw(value))) => value.raw.trim().eq_ignore_ascii_case("7bit"),
+        Some((_, HeaderType::Text(value))) => value.text.trim().eq_ignore_ascii_case("7bit"),
+        Some(_) => false,
+        None => encoding == Some(EncodingType::None),
+    };
+
+    if is_7bit && !contents.is_ascii() {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "part declared as 7bit contains non-ASCII bytes",
+        ))
+    } else {
+        Ok(())
+    }
+}
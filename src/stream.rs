@@ -0,0 +1,450 @@
+/*
+ * Copyright Stalwart Labs Ltd. See the COPYING
+ * file at the top-level directory of this distribution.
+ *
+ * Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+ * https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+ * <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+ * option. This file may not be copied, modified, or distributed
+ * except according to those terms.
+ */
+
+use alloc::{borrow::Cow, format, string::String, vec::Vec};
+
+use crate::{
+    encoders::{CountingWriter, LineEndingWriter},
+    headers::{
+        check_header_name,
+        content_type::ContentType,
+        is_token,
+        message_id::{default_hostname, generate_message_id, MessageId},
+        raw::Raw,
+        write_header_line, HeaderType,
+    },
+    io::{self, Write},
+    mime::{make_boundary, BodyPart, MimePart},
+    LineEnding, WriteOptions,
+};
+
+/// Builds a message by writing its headers and parts as they are added,
+/// so that large messages can be streamed with bounded memory.
+///
+/// Headers are written first, followed by either a single part or any
+/// number of nested multiparts. Message-ID, Date and MIME-Version headers
+/// are added when the body starts, unless already set. Calling `finish`
+/// closes all open multiparts.
+pub struct StreamBuilder<W: Write> {
+    output: LineEndingWriter<CountingWriter<W>>,
+    options: WriteOptions,
+    boundaries: Vec<String>,
+    state: State,
+    has_date: bool,
+    has_message_id: bool,
+    has_mime_version: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum State {
+    Headers,
+    Body,
+    Done,
+}
+
+/// Input bytes encoded per read, a multiple of the 57 bytes that make up
+/// a line of Base64.
+#[cfg(feature = "std")]
+const STREAM_CHUNK_LEN: usize = 57 * 1024;
+
+impl<W: Write> StreamBuilder<W> {
+    /// Create a new StreamBuilder writing to `output`.
+    pub fn new(output: W) -> Self {
+        StreamBuilder {
+            output: LineEndingWriter::new(CountingWriter::new(output), LineEnding::CrLf),
+            options: WriteOptions::default(),
+            boundaries: Vec::new(),
+            state: State::Headers,
+            has_date: false,
+            has_message_id: false,
+            has_mime_version: false,
+        }
+    }
+
+    /// Set the options used when writing the message.
+    pub fn write_options(mut self, value: WriteOptions) -> Self {
+        // Everything written so far ends with a line break, so no CR is held back
+        self.output.line_ending = value.line_ending;
+        self.options = value;
+        self
+    }
+
+    /// Write a message header. Headers must be written before any part.
+    pub fn header<'x>(
+        mut self,
+        header: impl AsRef<str>,
+        value: impl Into<HeaderType<'x>>,
+    ) -> io::Result<Self> {
+        let header = header.as_ref();
+        check_header_name(header)?;
+        if self.state != State::Headers {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "headers must be written before the body",
+            ));
+        } else if self.output.get_ref().count() == 0 {
+            self.options.validate()?;
+        }
+
+        if header.eq_ignore_ascii_case("Date") {
+            self.has_date = true;
+        } else if header.eq_ignore_ascii_case("Message-ID") {
+            self.has_message_id = true;
+        } else if header.eq_ignore_ascii_case("MIME-Version") {
+            if self.has_mime_version {
+                return Ok(self);
+            }
+            self.has_mime_version = true;
+        }
+
+        write_header_line(&mut self.output, header, &value.into(), &self.options)?;
+        Ok(self)
+    }
+
+    /// Start a multipart with the given subtype, such as `mixed` or
+    /// `alternative`. Parts added next are written inside it until
+    /// `end_multipart` is called. Returns an error if the subtype is not
+    /// a valid token.
+    pub fn begin_multipart(mut self, subtype: &str) -> io::Result<Self> {
+        if !is_token(subtype) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid multipart subtype {subtype:?}"),
+            ));
+        }
+        self.begin_part()?;
+        let boundary = make_boundary("_");
+        write_header_line(
+            &mut self.output,
+            "Content-Type",
+            &ContentType::new(format!("multipart/{subtype}")).attribute("boundary", &boundary),
+            &self.options,
+        )?;
+        self.output.write_all(b"\r\n")?;
+        self.boundaries.push(boundary);
+        Ok(self)
+    }
+
+    /// Close the innermost open multipart.
+    pub fn end_multipart(mut self) -> io::Result<Self> {
+        let boundary = self.boundaries.pop().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "no open multipart to end")
+        })?;
+        self.output.write_all(b"\r\n--")?;
+        self.output.write_all(boundary.as_bytes())?;
+        self.output.write_all(b"--")?;
+        if !self.boundaries.is_empty() {
+            self.output.write_all(b"\r\n")?;
+        } else {
+            if self.options.trailing_crlf != Some(false) {
+                self.output.write_all(b"\r\n")?;
+            }
+            self.state = State::Done;
+        }
+        Ok(self)
+    }
+
+    /// Write a MIME part, which may itself be a multipart.
+    pub fn part(mut self, part: MimePart<'_>) -> io::Result<Self> {
+        let is_top_level = self.begin_part()?;
+        // The part converts its own line endings
+        self.output.set_line_ending(LineEnding::CrLf)?;
+        if is_top_level {
+            part.write_part_with(&mut self.output, &self.options)?;
+            self.state = State::Done;
+        } else {
+            // Trailing CRLF options only apply to the end of the message
+            let options = WriteOptions {
+                trailing_crlf: None,
+                ..self.options.clone()
+            };
+            part.write_part_with(&mut self.output, &options)?;
+        }
+        self.output.set_line_ending(self.options.line_ending)?;
+        Ok(self)
+    }
+
+    /// Write a text part with the given content type.
+    pub fn text_part<'x>(
+        self,
+        content_type: impl Into<ContentType<'x>>,
+        text: impl Into<Cow<'x, str>>,
+    ) -> io::Result<Self> {
+        self.part(MimePart::new(content_type, BodyPart::Text(text.into())))
+    }
+
+    /// Write a binary part, Base64-encoding the contents of `reader` as
+    /// they are read.
+    #[cfg(feature = "std")]
+    pub fn binary_part<'x>(
+        self,
+        content_type: impl Into<ContentType<'x>>,
+        reader: impl std::io::Read,
+    ) -> io::Result<Self> {
+        self.stream_part(content_type.into(), None, reader)
+    }
+
+    /// Write an attachment, Base64-encoding the contents of `reader` as
+    /// they are read.
+    #[cfg(feature = "std")]
+    pub fn attachment<'x>(
+        self,
+        content_type: impl Into<ContentType<'x>>,
+        filename: impl Into<Cow<'x, str>>,
+        reader: impl std::io::Read,
+    ) -> io::Result<Self> {
+        self.stream_part(content_type.into(), Some(filename.into()), reader)
+    }
+
+    /// Close all open multiparts and return the number of bytes written.
+    /// A message without parts gets an empty text body.
+    pub fn finish(mut self) -> io::Result<usize> {
+        if self.state == State::Headers {
+            self = self.part(MimePart::new("text/plain", "\n"))?;
+        }
+        while !self.boundaries.is_empty() {
+            self = self.end_multipart()?;
+        }
+        if self.options.trailing_crlf == Some(true)
+            && !self
+                .output
+                .get_ref()
+                .ends_with_line_ending(self.options.line_ending)
+        {
+            self.output.write_all(b"\r\n")?;
+        }
+        let mut output = self.output.finish()?;
+        output.flush()?;
+        Ok(output.count())
+    }
+
+    #[cfg(feature = "std")]
+    fn stream_part(
+        mut self,
+        content_type: ContentType<'_>,
+        filename: Option<Cow<'_, str>>,
+        mut reader: impl std::io::Read,
+    ) -> io::Result<Self> {
+        let is_top_level = self.begin_part()?;
+        write_header_line(
+            &mut self.output,
+            "Content-Type",
+            &content_type,
+            &self.options,
+        )?;
+        write_header_line(
+            &mut self.output,
+            "Content-Transfer-Encoding",
+            &Raw::new("base64"),
+            &self.options,
+        )?;
+        if let Some(filename) = filename {
+            write_header_line(
+                &mut self.output,
+                "Content-Disposition",
+                &ContentType::new("attachment").attribute("filename", filename),
+                &self.options,
+            )?;
+        }
+        self.output.write_all(b"\r\n")?;
+
+        let mut buf = alloc::vec![0u8; STREAM_CHUNK_LEN];
+        loop {
+            // Only the last chunk may end with a partial line
+            let mut len = 0;
+            while len < buf.len() {
+                match reader.read(&mut buf[len..]) {
+                    Ok(0) => break,
+                    Ok(bytes_read) => len += bytes_read,
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => (),
+                    Err(err) => return Err(err),
+                }
+            }
+            if len == 0 {
+                break;
+            }
+            crate::encoders::base64::base64_encode_mime(&buf[..len], &mut self.output, false)?;
+            if len < buf.len() {
+                break;
+            }
+        }
+
+        if is_top_level {
+            self.state = State::Done;
+        }
+        Ok(self)
+    }
+
+    /// Write the delimiter of a new part, finishing the message headers if
+    /// needed. Returns `true` if the part is the message body itself.
+    fn begin_part(&mut self) -> io::Result<bool> {
+        match self.state {
+            State::Headers => {
+                self.end_headers()?;
+                self.state = State::Body;
+            }
+            State::Body => (),
+            State::Done => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "the message body has already been written",
+                ))
+            }
+        }
+
+        if let Some(boundary) = self.boundaries.last() {
+            self.output.write_all(b"\r\n--")?;
+            self.output.write_all(boundary.as_bytes())?;
+            self.output.write_all(b"\r\n")?;
+            Ok(false)
+        } else {
+            Ok(true)
+        }
+    }
+
+    fn end_headers(&mut self) -> io::Result<()> {
+        if self.output.get_ref().count() == 0 {
+            self.options.validate()?;
+        }
+        if !self.has_message_id {
+            write_header_line(
+                &mut self.output,
+                "Message-ID",
+                &MessageId::new(generate_message_id(&default_hostname())),
+                &self.options,
+            )?;
+        }
+        #[cfg(feature = "std")]
+        if !self.has_date {
+            write_header_line(
+                &mut self.output,
+                "Date",
+                &crate::headers::date::Date::now(),
+                &self.options,
+            )?;
+        }
+        if !self.has_mime_version {
+            write_header_line(
+                &mut self.output,
+                "MIME-Version",
+                &Raw::new("1.0"),
+                &self.options,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{string::String, vec::Vec};
+
+    use mail_parser::MessageParser;
+    #[cfg(feature = "std")]
+    use mail_parser::MimeHeaders;
+
+    use crate::stream::StreamBuilder;
+    #[cfg(feature = "std")]
+    use crate::{headers::address::Address, mime::MimePart};
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn stream_message() {
+        let attachment = (0..=255u8).cycle().take(200_003).collect::<Vec<_>>();
+
+        let mut output = Vec::new();
+        let bytes_written = StreamBuilder::new(&mut output)
+            .header("From", Address::from("john@doe.com"))
+            .unwrap()
+            .header("Subject", crate::headers::text::Text::new("Streamed"))
+            .unwrap()
+            .begin_multipart("mixed")
+            .unwrap()
+            .begin_multipart("alternative")
+            .unwrap()
+            .text_part("text/plain", "Hello, world!")
+            .unwrap()
+            .part(MimePart::new("text/html", "<p>Hello, world!</p>"))
+            .unwrap()
+            .end_multipart()
+            .unwrap()
+            .attachment("application/octet-stream", "data.bin", &attachment[..])
+            .unwrap()
+            .finish()
+            .unwrap();
+        assert_eq!(bytes_written, output.len());
+        assert!(output.ends_with(b"--\r\n"));
+
+        let message = MessageParser::new().parse(&output).unwrap();
+        assert_eq!(message.subject(), Some("Streamed"));
+        assert_eq!(message.body_text(0).unwrap(), "Hello, world!");
+        assert_eq!(message.body_html(0).unwrap(), "<p>Hello, world!</p>");
+        let part = message.attachment(0).unwrap();
+        assert_eq!(part.attachment_name(), Some("data.bin"));
+        assert_eq!(part.contents(), attachment);
+
+        // The stream matches the encoding of a regular part
+        let mut expected = Vec::new();
+        MimePart::new("application/octet-stream", &attachment[..])
+            .attachment("data.bin")
+            .write_part(&mut expected)
+            .unwrap();
+        let mut streamed = Vec::new();
+        StreamBuilder::new(&mut streamed)
+            .binary_part("application/octet-stream", &attachment[..])
+            .unwrap()
+            .finish()
+            .unwrap();
+        let body = |message: &[u8]| {
+            let pos = message.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+            message[pos..].to_vec()
+        };
+        assert_eq!(body(&streamed), body(&expected));
+    }
+
+    #[test]
+    fn stream_errors() {
+        let builder = StreamBuilder::new(Vec::new())
+            .text_part("text/plain", "Body")
+            .unwrap();
+        assert!(StreamBuilder::new(Vec::new())
+            .text_part("text/plain", "Body")
+            .unwrap()
+            .text_part("text/plain", "Another body")
+            .is_err());
+        assert!(builder
+            .header("Subject", crate::headers::raw::Raw::new("Late"))
+            .is_err());
+        assert!(StreamBuilder::new(Vec::new()).end_multipart().is_err());
+        for subtype in ["", "mixed\r\nBcc: evil@x.com", "mixed; a=b", "mi xed"] {
+            assert!(StreamBuilder::new(Vec::new())
+                .begin_multipart(subtype)
+                .is_err_and(|err| err.kind() == crate::io::ErrorKind::InvalidInput));
+        }
+        for name in ["", "X-Test\r\nBcc", "X Test", "X:Test"] {
+            assert!(StreamBuilder::new(Vec::new())
+                .header(name, crate::headers::raw::Raw::new("value"))
+                .is_err());
+        }
+
+        let mut output = Vec::new();
+        StreamBuilder::new(&mut output).finish().unwrap();
+        let message = MessageParser::new().parse(&output).unwrap();
+        assert!(message.message_id().is_some());
+        assert_eq!(
+            String::from_utf8(output)
+                .unwrap()
+                .matches("MIME-Version")
+                .count(),
+            1
+        );
+    }
+}